@@ -2,151 +2,548 @@ extern crate chifir;
 extern crate termion;
 
 use termion::raw::IntoRawMode;
-use termion::async_stdin;
+use termion::{async_stdin, AsyncReader};
 
-use std::io::{self, Write};
+use chifir::compiler::{self, Compiler, CompilerError};
+use chifir::computer::{Computer, StopReason};
+use chifir::debugger::Debugger;
+use chifir::display::{FrameFiles, Sixel};
+use chifir::linker::Linker;
+use chifir::netpbm::Format;
+use chifir::object::{self, Object};
+use chifir::script::Script;
+use chifir::snapshot::Snapshot;
+use chifir::symbols::Symbols;
+use chifir::trace::TraceReader;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+const DEMO: &str = "
+; Configure a 16x16 pixel display
+cfv display 10 10
+
+check-key:
+  drw
+  key x
+
+  ; Exit if Ctrl+C is pressed
+  sub y x ctrl-c
+  beq /3 y exit
+
+  ; Print 'A' if 'a' is pressed
+  sub y x letter-a
+  beq /3 y render-a
+
+  ; Clear the display if anything else was pressed
+  lpc /2 clear-display
+
+exit:
+  brk
+
+; Registers
+x:
+  .word 0
+y:
+  .word 0
+z:
+  .word 0
+zz:
+  .word 0
+k:
+  .word 0
+kk:
+  .word 0
+
+; Constants
+ctrl-c:
+  .word 3
+letter-a:
+  .word 'a'
+one:
+  .word 1
+
+clear-display:
+  lea k /3 100
+  lea kk /3 display
+
+.loop:
+  add x k kk
+  sra /3 x 0
+  beq /3 k check-key
+  sub k k one
+  lpc /2 .loop
+
+render-a:
+  lea k /3 100
+  lea kk /3 display
+  lea zz /3 font-a
+
+.loop:
+  add x k kk
+  add y k zz
+  lra z y
+  sra z x
+  beq /3 k check-key
+  sub k k one
+  lpc /2 .loop
+
+font-a:
+  .word 0 0 0 0 0 1 1 1 1 1 1 0 0 0 0 0
+  .word 0 0 0 0 0 1 1 1 1 1 1 0 0 0 0 0
+  .word 0 0 0 0 0 1 1 1 1 1 1 0 0 0 0 0
+  .word 0 0 1 1 1 0 0 0 0 0 0 1 1 1 0 0
+  .word 0 0 1 1 1 0 0 0 0 0 0 1 1 1 0 0
+  .word 0 0 1 1 1 0 0 0 0 0 0 1 1 1 0 0
+  .word 0 0 1 1 1 1 1 1 1 1 1 1 1 1 0 0
+  .word 0 0 1 1 1 1 1 1 1 1 1 1 1 1 0 0
+  .word 0 0 1 1 1 1 1 1 1 1 1 1 1 1 0 0
+  .word 0 0 1 1 1 0 0 0 0 0 0 1 1 1 0 0
+  .word 0 0 1 1 1 0 0 0 0 0 0 1 1 1 0 0
+  .word 0 0 1 1 1 0 0 0 0 0 0 1 1 1 0 0
+  .word 0 0 1 1 1 0 0 0 0 0 0 1 1 1 0 0
+  .word 0 0 1 1 1 0 0 0 0 0 0 1 1 1 0 0
+  .word 0 0 1 1 1 0 0 0 0 0 0 1 1 1 0 0
+
+display:
+  brk
+";
+
+const USAGE: &str = "\
+Usage:
+  chifir [--fit]                          Run the built in keyboard demo,
+                                          optionally scaled to fit the
+                                          terminal
+  chifir run <file.asm> [--include <dir>] [--trace <file>] [--save <file>]
+             [--restore <file>] [--script <file>] [--scale <factor> | --fit]
+             [--headless [--frames <dir>] [--format pbm|pgm|ppm]]
+                                          Run a program, optionally saving a
+                                          snapshot when it stops, resuming
+                                          from one or pressing scripted keys.
+                                          The display can be scaled up by a
+                                          decimal factor or to fit the
+                                          terminal.
+                                          Headless runs don't use the terminal
+                                          and can save every frame as an image.
+                                          Files that aren't next to the one
+                                          including them are looked for in
+                                          the include directory
+  chifir debug <file.asm> [--include <dir>]
+                                          Debug a program
+  chifir trace <file> [--source <file.asm> [--include <dir>]] [--pc <address>]
+               [--address <address>]
+                                          Print a trace recorded by run
+  chifir asm <file.asm> [--include <dir>] [--output <file.o>] [--listing] [--map]
+                                          Compile a program into a
+                                          relocatable object, optionally
+                                          writing a listing of every line's
+                                          address and words to <file.lst>
+                                          and the address of every label to
+                                          <file.map>
+  chifir link <file.o>... --output <file>
+                                          Link objects into a program
+
+Programs linked with link can be used anywhere a <file.asm> can.";
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(|arg| arg.as_str()) {
+        None => demo(Scale::Factor(1)),
+        Some("--fit") if args.len() == 1 => demo(Scale::Fit),
+        Some("run") => run(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("trace") => trace(&args[1..]),
+        Some("asm") => asm(&args[1..]),
+        Some("link") => link(&args[1..]),
+        Some(_) => fail(USAGE),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+// Command line arguments split into a single file name, `--name value`
+// options and `--name` flags. Flags are stored as options with an empty value.
+struct Args {
+    path: String,
+    options: HashMap<String, String>,
+}
+
+fn parse_args(args: &[String], names: &[&str], flags: &[&str]) -> Args {
+    let (mut paths, options) = parse_files(args, names, flags);
+    if paths.len() != 1 {
+        fail(USAGE);
+    }
+
+    Args {
+        path: paths.remove(0),
+        options,
+    }
+}
+
+// Splits command line arguments like `parse_args`, for commands that take
+// any number of files.
+fn parse_files(args: &[String], names: &[&str], flags: &[&str]) -> (Vec<String>, HashMap<String, String>) {
+    let mut paths = Vec::new();
+    let mut options = HashMap::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if names.contains(&arg.as_str()) {
+            match args.next() {
+                Some(value) => options.insert(arg.clone(), value.clone()),
+                None => fail(&format!("{} needs a value\n\n{}", arg, USAGE)),
+            };
+        } else if flags.contains(&arg.as_str()) {
+            options.insert(arg.clone(), String::new());
+        } else if arg.starts_with("--") {
+            fail(USAGE);
+        } else {
+            paths.push(arg.clone());
+        }
+    }
+
+    if paths.is_empty() {
+        fail(USAGE);
+    }
+    (paths, options)
+}
+
+// Compiles the program at `path`, or reads it if it's a linked program,
+// returning its bytecodes and labels.
+fn load(path: &str, include: Option<&String>) -> (Vec<u32>, HashMap<String, u32>) {
+    let bytes = fs::read(path).unwrap_or_else(|error| fail(&format!("{}: {}", path, error)));
+    if !object::is_object(&bytes) {
+        let (compiler, bytecodes) = compile(Compiler::new(), path, include);
+        return (bytecodes, compiler.labels().clone());
+    }
+
+    // Linking a lone object checks that it doesn't need any others.
+    let object = Object::read_from(bytes.as_slice())
+        .unwrap_or_else(|error| fail(&format!("{}: {}", path, error)));
+    let program = match Linker::new().object(path, object).link() {
+        Ok(program) => program,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            process::exit(1);
+        }
+    };
+
+    (program.code, program.exports.into_iter().collect())
+}
+
+// Compiles the file at `path`, printing every problem and stopping if the
+// program doesn't compile.
+fn compile(mut compiler: Compiler, path: &str, include: Option<&String>) -> (Compiler, Vec<u32>) {
+    if let Some(directory) = include {
+        compiler = compiler.include_dir(directory);
+    }
+
+    let bytecodes = match compiler.compile_file(path) {
+        Ok(bytecodes) => bytecodes.to_vec(),
+        Err(CompilerError::Diagnostics(diagnostics)) => {
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic);
+            }
+            process::exit(1);
+        }
+        Err(error) => fail(&error.to_string()),
+    };
+
+    (compiler, bytecodes)
+}
+
+fn asm(args: &[String]) {
+    let args = parse_args(args, &["--include", "--output"], &["--listing", "--map"]);
+    let output = match args.options.get("--output") {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(&args.path).with_extension("o"),
+    };
+
+    let (compiler, _) = compile(Compiler::new().relocatable(), &args.path, args.options.get("--include"));
+    write_object(&output, compiler.object().unwrap());
+
+    // The listing and map are named after the object.
+    if args.options.contains_key("--listing") {
+        let listing: String = compiler.listing().iter().map(|line| format!("{}\n", line)).collect();
+        write_text(&output.with_extension("lst"), &listing);
+    }
+    if args.options.contains_key("--map") {
+        write_text(&output.with_extension("map"), &compiler.symbol_map());
+    }
+}
+
+fn write_text(path: &Path, text: &str) {
+    if let Err(error) = fs::write(path, text) {
+        fail(&format!("{}: {}", path.display(), error));
+    }
+}
+
+fn link(args: &[String]) {
+    let (paths, options) = parse_files(args, &["--output"], &[]);
+    let output = match options.get("--output") {
+        Some(output) => output,
+        None => fail(&format!("link needs --output\n\n{}", USAGE)),
+    };
+
+    let mut linker = Linker::new();
+    for path in paths {
+        let object = File::open(&path)
+            .and_then(|file| Object::read_from(BufReader::new(file)))
+            .unwrap_or_else(|error| fail(&format!("{}: {}", path, error)));
+        linker = linker.object(path, object);
+    }
+
+    match linker.link() {
+        Ok(program) => write_object(Path::new(output), &program),
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn write_object(path: &Path, object: &Object) {
+    if let Err(error) = File::create(path).and_then(|file| object.write_to(BufWriter::new(file))) {
+        fail(&format!("{}: {}", path.display(), error));
+    }
+}
+
+fn debug(args: &[String]) {
+    let args = parse_args(args, &["--include"], &[]);
+
+    let (bytecodes, labels) = load(&args.path, args.options.get("--include"));
+    let mut computer = Computer::new().history(10_000, 100);
+    computer.load(bytecodes);
+
+    let mut debugger = Debugger::new(computer, labels);
+    let stdin = io::stdin();
+    let mut last = String::new();
+
+    loop {
+        print!("(chifir) ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            println!();
+            break;
+        }
+
+        // An empty line repeats the last command.
+        let line = line.trim();
+        if !line.is_empty() {
+            last = line.to_string();
+        }
+
+        match last.as_str() {
+            "quit" | "q" => break,
+            command => {
+                let output = debugger.execute(command);
+                if !output.is_empty() {
+                    println!("{}", output);
+                }
+            }
+        }
+    }
+}
+
+fn run(args: &[String]) {
+    let args = parse_args(args,
+                          &["--trace", "--save", "--restore", "--script", "--frames", "--format",
+                            "--scale", "--include"],
+                          &["--headless", "--fit"]);
+    let headless = args.options.contains_key("--headless");
+    if !headless && (args.options.contains_key("--frames") || args.options.contains_key("--format")) {
+        fail(&format!("--frames and --format need --headless\n\n{}", USAGE));
+    }
+    if headless && (args.options.contains_key("--scale") || args.options.contains_key("--fit")) {
+        fail(&format!("--scale and --fit need a terminal\n\n{}", USAGE));
+    }
+
+    // Factors are decimal, so `--scale 10` means what it looks like.
+    let scale = match (args.options.get("--scale"), args.options.contains_key("--fit")) {
+        (Some(_), true) => fail(&format!("--scale and --fit can't be used together\n\n{}", USAGE)),
+        (Some(factor), false) => {
+            match factor.parse::<u32>() {
+                Ok(factor) if factor > 0 => Scale::Factor(factor),
+                _ => fail(&format!("invalid scale factor `{}`", factor)),
+            }
+        }
+        (None, true) => Scale::Fit,
+        (None, false) => Scale::Factor(1),
+    };
+    let (bytecodes, _) = load(&args.path, args.options.get("--include"));
+
+    let snapshot = args.options.get("--restore").map(|path| {
+        File::open(path)
+            .and_then(|file| Snapshot::read_from(BufReader::new(file)))
+            .unwrap_or_else(|error| fail(&format!("{}: {}", path, error)))
+    });
+
+    let mut computer = Computer::new();
+    if let Some(path) = args.options.get("--trace") {
+        computer = File::create(path)
+            .and_then(|file| computer.trace(Box::new(BufWriter::new(file))))
+            .unwrap_or_else(|error| fail(&format!("{}: {}", path, error)));
+    }
+
+    let scripted = args.options.contains_key("--script");
+    if let Some(path) = args.options.get("--script") {
+        let mut text = String::new();
+        if let Err(error) = File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+            fail(&format!("{}: {}", path, error));
+        }
+        match Script::parse(&text) {
+            Ok(script) => computer = computer.script(script),
+            Err(error) => fail(&format!("{}: {}", path, error)),
+        }
+    }
+
+    if let Some(directory) = args.options.get("--frames") {
+        let format = match args.options.get("--format") {
+            Some(format) => {
+                Format::from_extension(format)
+                    .unwrap_or_else(|| fail(&format!("unknown image format `{}`", format)))
+            }
+            None => Format::Pbm,
+        };
+
+        if let Err(error) = fs::create_dir_all(directory) {
+            fail(&format!("{}: {}", directory, error));
+        }
+        computer = computer.display(Box::new(FrameFiles::new(directory, format)));
+    }
+
+    let computer = if headless {
+        start(computer, &bytecodes, snapshot.as_ref(), true)
+    } else {
+        execute(computer, &bytecodes, snapshot.as_ref(), scripted, scale)
+    };
+
+    if let (Some(path), Some(error)) = (args.options.get("--trace"), computer.trace_error()) {
+        fail(&format!("{}: {}", path, error));
+    }
+    if let Some(error) = computer.display_error() {
+        fail(&error.to_string());
+    }
+
+    if let Some(path) = args.options.get("--save") {
+        if let Err(error) = File::create(path)
+            .and_then(|file| computer.snapshot().write_to(BufWriter::new(file))) {
+            fail(&format!("{}: {}", path, error));
+        }
+    }
+}
+
+fn trace(args: &[String]) {
+    let args = parse_args(args, &["--source", "--include", "--pc", "--address"], &[]);
+    if args.options.contains_key("--include") && !args.options.contains_key("--source") {
+        fail(&format!("--include needs --source\n\n{}", USAGE));
+    }
+
+    let labels = match args.options.get("--source") {
+        Some(source) => load(source, args.options.get("--include")).1,
+        None => HashMap::new(),
+    };
+    let symbols = Symbols::new(labels);
+
+    let resolve = |name: &str| {
+        args.options.get(name).map(|address| {
+            symbols.resolve(address).unwrap_or_else(|error| fail(&error))
+        })
+    };
+    let pc = resolve("--pc");
+    let address = resolve("--address");
+
+    let file = File::open(&args.path).unwrap_or_else(|error| {
+        fail(&format!("{}: {}", args.path, error))
+    });
+    let reader = TraceReader::new(file).unwrap_or_else(|error| {
+        fail(&format!("{}: {}", args.path, error))
+    });
+
     let stdout = io::stdout();
-    let mut stdout = Box::new(stdout.into_raw_mode().unwrap());
+    let mut stdout = stdout.lock();
+
+    for record in reader {
+        let record = record.unwrap_or_else(|error| fail(&format!("{}: {}", args.path, error)));
+
+        if pc.is_some() && pc != Some(record.pc) {
+            continue;
+        }
+        if address.is_some() && address != record.store.map(|store| store.address) {
+            continue;
+        }
+
+        let opcode = match compiler::mnemonic(record.opcode) {
+            Some(mnemonic) => mnemonic.to_string(),
+            None => format!("{:x}", record.opcode),
+        };
+
+        let mut line = format!("{}: {} {:x} {:x} {:x}",
+                               symbols.describe(record.pc),
+                               opcode,
+                               record.a,
+                               record.b,
+                               record.c);
+
+        if let Some(key) = record.key {
+            line.push_str(&format!("  key {:02x}", key));
+        }
+
+        if let Some(store) = record.store {
+            line.push_str(&format!("  M[{}] {:08x} -> {:08x}",
+                                   symbols.describe(store.address),
+                                   store.old,
+                                   store.new));
+        }
 
-    let mut compiler = chifir::compiler::Compiler::new();
-    write!(compiler, "{}", "
-    ; Configure a 16x16 pixel display
-    cfv display 10 10
-
-    check-key:
-      drw
-      key x
-
-      ; Exit if Ctrl+C is pressed
-      sub y x ctrl-c
-      beq /3 y exit
-
-      ; Print 'A' if 'a' is pressed
-      sub y x letter-a
-      beq /3 y render-a
-
-      ; Clear the display if anything else was pressed
-      lpc /2 clear-display
-
-    exit:
-      brk
-
-    ; Registers
-    x:
-      nop
-    y:
-      nop
-    z:
-      nop
-    zz:
-      nop
-    k:
-      nop
-    kk:
-      nop
-
-    ; Constants
-    ctrl-c:
-      3
-    letter-a:
-      61
-    one:
-      1
-
-    clear-display:
-      lea k /3 100
-      lea kk /3 display
-
-    clear-display-loop:
-      add x k kk
-      sra /3 x 0
-      beq /3 k check-key
-      sub k k one
-      lpc /2 clear-display-loop
-
-    render-a:
-      lea k /3 100
-      lea kk /3 display
-      lea zz /3 font-a
-
-    render-a-loop:
-      add x k kk
-      add y k zz
-      lra z y
-      sra z x
-      beq /3 k check-key
-      sub k k one
-      lpc /2 render-a-loop
-
-    font-a:
-      0 0 0 0
-      0 1 1 1
-      1 1 1 0
-      0 0 0 0
-      0 0 0 0
-      0 1 1 1
-      1 1 1 0
-      0 0 0 0
-      0 0 0 0
-      0 1 1 1
-      1 1 1 0
-      0 0 0 0
-      0 0 1 1
-      1 0 0 0
-      0 0 0 1
-      1 1 0 0
-      0 0 1 1
-      1 0 0 0
-      0 0 0 1
-      1 1 0 0
-      0 0 1 1
-      1 0 0 0
-      0 0 0 1
-      1 1 0 0
-      0 0 1 1
-      1 1 1 1
-      1 1 1 1
-      1 1 0 0
-      0 0 1 1
-      1 1 1 1
-      1 1 1 1
-      1 1 0 0
-      0 0 1 1
-      1 1 1 1
-      1 1 1 1
-      1 1 0 0
-      0 0 1 1
-      1 0 0 0
-      0 0 0 1
-      1 1 0 0
-      0 0 1 1
-      1 0 0 0
-      0 0 0 1
-      1 1 0 0
-      0 0 1 1
-      1 0 0 0
-      0 0 0 1
-      1 1 0 0
-      0 0 1 1
-      1 0 0 0
-      0 0 0 1
-      1 1 0 0
-      0 0 1 1
-      1 0 0 0
-      0 0 0 1
-      1 1 0 0
-      0 0 1 1
-      1 0 0 0
-      0 0 0 1
-      1 1 0 0
-
-    display:
-      brk
-    ").unwrap();
+        // Stop quietly when the output is closed, like when piping to head.
+        if writeln!(stdout, "{}", line).is_err() {
+            break;
+        }
+    }
+}
+
+fn demo(scale: Scale) {
+    let mut compiler = Compiler::new();
+    compiler.write_all(DEMO.as_bytes()).unwrap();
 
     let bytecodes = compiler.compile().unwrap();
+    execute(Computer::new(), bytecodes, None, false, scale);
+}
+
+// How much to scale the display up by on the terminal.
+enum Scale {
+    Factor(u32),
+    Fit,
+}
+
+// Runs a program with the keyboard and screen hooked up to the terminal,
+// starting from `snapshot` if there is one. Scripted runs ignore the keyboard
+// and stop once the program waits for a key the script will never press.
+fn execute(computer: Computer,
+           bytecodes: &[u32],
+           snapshot: Option<&Snapshot>,
+           scripted: bool,
+           scale: Scale)
+           -> Computer {
+    let stdout = io::stdout();
+    let mut stdout = Box::new(stdout.into_raw_mode().unwrap());
 
     write!(stdout,
            "{}{}",
@@ -155,12 +552,76 @@ fn main() {
         .unwrap();
     stdout.flush().unwrap();
 
-    let stdin = Box::new(async_stdin());
+    let mut stdin = if !scripted || matches!(scale, Scale::Fit) {
+        Some(async_stdin())
+    } else {
+        None
+    };
+
+    let display = match scale {
+        Scale::Factor(factor) => Sixel::new(stdout).scale(factor),
+        Scale::Fit => {
+            match stdin.as_mut().and_then(|stdin| terminal_pixels(&mut stdout, stdin)) {
+                Some((width, height)) => Sixel::new(stdout).fit(width, height),
+                None => Sixel::new(stdout),
+            }
+        }
+    };
+
+    let mut vm = computer.display(Box::new(display));
+    if let (false, Some(stdin)) = (scripted, stdin) {
+        vm = vm.input(Box::new(stdin));
+    }
+
+    start(vm, bytecodes, snapshot, scripted)
+}
+
+// Asks the terminal for the width and height of its text area in pixels.
+// Terminals that don't answer within a fifth of a second are left unscaled.
+fn terminal_pixels<W: Write>(stdout: &mut W, stdin: &mut AsyncReader) -> Option<(u32, u32)> {
+    write!(stdout, "\x1b[14t").ok()?;
+    stdout.flush().ok()?;
+
+    // The answer looks like `ESC [ 4 ; height ; width t`.
+    let mut answer = Vec::new();
+    for _ in 0..20 {
+        let mut bytes = Vec::new();
+        stdin.read_to_end(&mut bytes).ok()?;
+        answer.extend(bytes);
 
-    let mut vm = chifir::computer::Computer::new().input(stdin).output(stdout);
+        if answer.ends_with(b"t") {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let answer = String::from_utf8(answer).ok()?;
+    let mut fields = answer.strip_prefix("\x1b[4;")?.strip_suffix('t')?.split(';');
+    let height = fields.next()?.parse().ok()?;
+    let width = fields.next()?.parse().ok()?;
+
+    Some((width, height))
+}
+
+// Loads and runs a program on a computer that already has its display and
+// keyboard bound.
+fn start(mut vm: Computer,
+         bytecodes: &[u32],
+         snapshot: Option<&Snapshot>,
+         scripted: bool)
+         -> Computer {
     vm.load_from_slice(bytecodes);
+    if let Some(snapshot) = snapshot {
+        vm.restore(snapshot);
+    }
 
-    while vm.next() != 0 {
-        vm.step();
+    while let StopReason::WaitingForInput { .. } = vm.run() {
+        if !scripted {
+            thread::sleep(Duration::from_millis(10));
+        } else if vm.pending_keys() == 0 {
+            break;
+        }
     }
+
+    vm
 }
@@ -58,14 +58,14 @@
 //! The term **PC** in the comments refers to the program counter. Chifir starts
 //! with the program counter at 0. The term **M[X]** in the comments refers to
 //! the **X**<sup>th</sup> location in memory. Memory is allocated when it's
-//! accessed, and programs can use up to 16 GiB of memory.
+//! written, and programs can use up to 16 GiB of memory.
 //!
 //! # Instructions
 //!
 //! Chifir instructions consist of an opcode followed by three operands. Both
-//! opcodes and operands are 32 bits and are written in hexadecimal. Because hex
-//! values for opcodes are hard to memorize, programs can use three letter
-//! abbreviations for opcodes instead.
+//! opcodes and operands are 32 bits and are usually written in hexadecimal.
+//! Because hex values for opcodes are hard to memorize, programs can use
+//! three letter abbreviations for opcodes instead.
 //!
 //! ```
 //! use std::io::Write;
@@ -92,6 +92,32 @@
 //!
 //! [Table 1](#table-1) has a full list of opcodes and their abbreviations.
 //!
+//! # Numbers
+//!
+//! Numbers are hex unless they say otherwise. A `0x`, `0d`, `0o` or `0b`
+//! prefix makes a number hex, decimal, octal or binary, and so does a `#` in
+//! front of a decimal number. The `.radix` directive changes what numbers
+//! without a prefix are for the lines after it. Its own operand is always
+//! decimal, so `.radix 16` goes back to hex.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//!
+//! let mut compiler = Compiler::new();
+//!
+//! write!(compiler, "{}","
+//! cfv 0x40 #16 0d16
+//! .radix 10
+//! 15 12 0b1010 0o17
+//! ").unwrap();
+//!
+//! assert_eq!([
+//! 0x11, 0x40, 0x10, 0x10,
+//! 0xf, 0xc, 0xa, 0xf
+//! ], compiler.compile().unwrap());
+//! ```
+//!
 //!
 //! # Labels
 //!
@@ -175,6 +201,308 @@
 //! ], bytecodes);
 //! ```
 //!
+//! A label can only be defined once, so labels starting with a `.` are
+//! local to the last label before them that doesn't. That way every routine
+//! can have its own `.loop`. Elsewhere, the local label is called by its full
+//! name, like `clear.loop`.
+//!
+//! Labels that are just a decimal number can be defined as often as needed.
+//! An operand like `@1b` refers to the closest `1:` before it and `@1f` to the
+//! closest `1:` after it. The `@` keeps them apart from hex numbers like
+//! `1b`.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//!
+//! let mut compiler = Compiler::new();
+//!
+//! write!(compiler, "{}","
+//! clear:
+//!   jmp @1f
+//! .loop:
+//! 1:
+//!   jmp .loop
+//!   jmp @1b
+//! ").unwrap();
+//!
+//! assert_eq!([
+//! 0x1, 0x2, 0x4, 0x0,
+//! 0x1, 0x6, 0x4, 0x0,
+//! 0x1, 0xa, 0x4, 0x0,
+//! ], compiler.compile().unwrap());
+//! assert_eq!(Some(&0x4), compiler.labels().get("clear.loop"));
+//! ```
+//!
+//! # Expressions
+//!
+//! Operands can do arithmetic on labels, numbers and characters with `+`,
+//! `-`, `*`, `/` and parentheses, as long as there are no spaces in them.
+//! A `/` in front of a value makes it relative to the opcode, so `/3` and
+//! `/(1+2)` are the same operand. Arithmetic wraps around at 32 bits.
+//!
+//! Label names can have dashes in them, so `end-start` only subtracts when
+//! there's no label called `end-start`.
+//!
+//! The `.equ` directive names the value of an expression. Constants are
+//! evaluated once every label is placed, so they can refer to labels and to
+//! each other before they're defined.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//!
+//! let mut compiler = Compiler::new();
+//!
+//! write!(compiler, "{}","
+//! .equ row 3
+//! .equ length end-start
+//! start:
+//!   .word display+(row*10) length /-1
+//! end:
+//! display:
+//! ").unwrap();
+//!
+//! assert_eq!([0x33, 0x3, 0xffffffff], compiler.compile().unwrap());
+//! assert_eq!(Some(&0x3), compiler.constants().get("length"));
+//! ```
+//!
+//! # Data
+//!
+//! Every instruction takes four words, which is a lot for a single constant.
+//! Directives lay out data word by word instead.
+//!
+//! |Directive          |Words                                               |
+//! |:------------------|:---------------------------------------------------|
+//! |`.word A B ...`    |One for every operand, which can be a label         |
+//! |`.fill count value`|`count` copies of `value`, which can be a label     |
+//! |`.zero count`      |`count` zeros                                       |
+//! |`.align n`         |Zeros up to the next address that's a multiple of n|
+//! |`.org address`     |Zeros up to `address`                               |
+//! |`.ascii "text"`    |One for every character                             |
+//! |`.asciz "text"`    |One for every character, then a zero                |
+//! |`.pack "text"`     |One for every four characters                       |
+//! |`.packz "text"`    |One for every four characters, ending in a zero byte|
+//!
+//! Counts and addresses have to be numbers, because they decide where labels
+//! end up.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//!
+//! let mut compiler = Compiler::new();
+//!
+//! write!(compiler, "{}","
+//!   lpc start
+//! ctrl-c:
+//!   .word 3
+//! start:
+//!   .word 0 0 0
+//!   .align 8
+//! mask:
+//!   .fill 2 ff
+//! ").unwrap();
+//!
+//! let bytecodes = compiler.compile().unwrap();
+//!
+//! assert_eq!([
+//! 0x1, 0x5, 0x0, 0x0,
+//! 0x3,
+//! 0x0, 0x0, 0x0,
+//! 0xff, 0xff
+//! ], bytecodes);
+//! assert_eq!(Some(&0x8), compiler.labels().get("mask"));
+//! ```
+//!
+//! Any operand can also be a quoted character like `'a'`, which stands for
+//! its ASCII code. That's the same number `key` stores when the key is
+//! pressed, so programs can compare key presses against `.word 'a'`. Strings
+//! are ASCII too, and `.pack` puts the first character of every four in the
+//! low byte of the word. Characters and strings understand the escapes `\n`,
+//! `\t`, `\r`, `\0`, `\\`, `\'` and `\"`.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//!
+//! let mut compiler = Compiler::new();
+//!
+//! write!(compiler, "{}", r#"
+//! letter-a:
+//!   .word 'a'
+//! message:
+//!   .asciz "Hi; bye"
+//!   .pack "Hi!"
+//! "#).unwrap();
+//!
+//! assert_eq!([
+//! 0x61,
+//! 0x48, 0x69, 0x3b, 0x20, 0x62, 0x79, 0x65, 0x0,
+//! 0x216948
+//! ], compiler.compile().unwrap());
+//! ```
+//!
+//! # Pseudo-instructions
+//!
+//! Some things programs do all the time take a few instructions, or a
+//! roundabout use of one. The compiler understands a handful of
+//! pseudo-instructions that expand into real ones. [Table 2](#table-2) lists
+//! them.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//! use chifir::computer::Computer;
+//!
+//! let mut compiler = Compiler::new();
+//!
+//! write!(compiler, "{}","
+//!   call double
+//!   brk
+//!
+//! double:
+//!   nop              ; Holds the return address
+//!   add x x x
+//!   ret double
+//!
+//! x:
+//!   15
+//! ").unwrap();
+//!
+//! let mut computer = Computer::new();
+//! computer.load_from_slice(compiler.compile().unwrap());
+//! computer.run();
+//!
+//! assert_eq!(0x2a, computer.peek(compiler.labels()["x"]));
+//! ```
+//!
+//! Relative operands of a pseudo-instruction count from its first word, just
+//! like they would for a real instruction. Some pseudo-instructions keep
+//! scratch values in the operands of a `nop` at the end of their expansion.
+//!
+//! Routines called with `call` must start with an instruction that doesn't
+//! use its A operand, like `nop`, because that's where the return address is
+//! kept. A routine can't call itself.
+//!
+//! # Macros
+//!
+//! Programs can define their own instructions as macros. A macro definition
+//! starts with `.macro`, the macro's name and the names of its parameters,
+//! and ends with `.endm`. Every use of the macro is replaced by the lines in
+//! between, with each parameter replaced by the matching operand.
+//!
+//! Labels starting with `%` belong to a single expansion of a macro, so a
+//! macro can jump around inside itself however many times it's used. Macros
+//! can use other macros, and can be used before they're defined.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//! use chifir::computer::Computer;
+//!
+//! let mut compiler = Compiler::new();
+//!
+//! write!(compiler, "{}","
+//! .macro double x
+//!   add x x x
+//! .endm
+//!
+//! .macro quadruple x
+//!   double x
+//!   double x
+//! .endm
+//!
+//!   quadruple y
+//!   brk
+//!
+//! y:
+//!   3
+//! ").unwrap();
+//!
+//! let mut computer = Computer::new();
+//! computer.load_from_slice(compiler.compile().unwrap());
+//! computer.run();
+//!
+//! assert_eq!(0xc, computer.peek(compiler.labels()["y"]));
+//! ```
+//!
+//! Problems inside a macro are reported at the line in its body, with a note
+//! for every macro call that led there.
+//!
+//! # Includes
+//!
+//! Bigger programs can be split into files. `.include "file.asm"` is
+//! replaced by the lines of `file.asm`, which is looked for next to the file
+//! including it, then in every directory added with `Compiler::include_dir`.
+//! Assembly written to the compiler rather than read with `compile_file`
+//! includes files from the current directory.
+//!
+//! ```
+//! use std::env;
+//! use std::fs;
+//! use std::process;
+//! use chifir::compiler::Compiler;
+//!
+//! let directory = env::temp_dir().join(format!("chifir-include-example-{}", process::id()));
+//! fs::create_dir_all(directory.join("lib")).unwrap();
+//! fs::write(directory.join("lib/constants.asm"), "one:\n  .word 1\n").unwrap();
+//! fs::write(directory.join("main.asm"), "  add x x one\n  brk\nx:\n  .word 0\n.include \"constants.asm\"\n").unwrap();
+//!
+//! let mut compiler = Compiler::new().include_dir(directory.join("lib"));
+//! compiler.compile_file(directory.join("main.asm")).unwrap();
+//!
+//! assert_eq!(Some(&0x9), compiler.labels().get("one"));
+//! # fs::remove_dir_all(&directory).unwrap();
+//! ```
+//!
+//! # Objects
+//!
+//! Routines that are used by many programs can be compiled once into a
+//! relocatable `Object` with `Compiler::relocatable` and linked into each
+//! program with the `Linker`. `.global name` exports a label to the other
+//! objects and `.extern name` imports a symbol one of them exports.
+//!
+//! Every word whose value depends on where a label or an external symbol
+//! ends up gets a relocation. That includes relative operands, which depend
+//! on where the instruction ends up. Such a word can only hold a single
+//! address plus or minus a number, so `end-start` is fine but `start*2`
+//! isn't.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//! use chifir::object::Relocation;
+//!
+//! let mut compiler = Compiler::new().relocatable();
+//!
+//! write!(compiler, "{}","
+//! .global clear
+//! .extern screen
+//! clear:
+//!   .word screen+1 /2 end-clear
+//! end:
+//! ").unwrap();
+//!
+//! assert_eq!([0x1, 0x2, 0x3], compiler.compile().unwrap());
+//! assert_eq!(vec![
+//!     Relocation { address: 0x0, symbol: Some("screen".to_string()) },
+//!     Relocation { address: 0x1, symbol: None },
+//! ], compiler.object().unwrap().relocations);
+//! ```
+//!
+//! # Listings
+//!
+//! `Compiler::listing` pairs every line of a program with the address it was
+//! compiled to and the words it became, and `Compiler::symbol_map` lists
+//! every label by address. Both make it easier to find the line behind an
+//! address seen in the debugger or in a trace.
+//!
+//! # Diagnostics
+//!
+//! Rather than guessing what a mistyped opcode or a missing label was meant
+//! to be, `compile` reports every problem it finds as a `Diagnostic` with the
+//! file, line and column of the offending token.
 //!
 //! # Table 1
 //!
@@ -200,236 +528,1818 @@
 //! |15    |`key`       |Get the last key pressed and store it in M[A]            |
 //! |16    |`nop`       |Skip this instruction                                    |
 //! |17    |`cfv`       |Configure display at M[A] with width B and height C      |
+//!
+//!
+//! # Table 2
+//!
+//! Pseudo-instructions and what they do.
+//!
+//! |Pseudo-instruction|Semantics                                       |Instructions|
+//! |:-----------------|:-----------------------------------------------|:----------:|
+//! |`jmp A`           |PC &larr; A                                     |1           |
+//! |`jeq A B`         |If M[A] &equals; 0, then PC &larr; B            |1           |
+//! |`jne A B`         |If M[A] &ne; 0, then PC &larr; B                |2           |
+//! |`mov A B`         |M[A] &larr; M[B]                                |1           |
+//! |`not A B`         |M[A] &larr; NOT M[B]                            |1           |
+//! |`and A B C`       |M[A] &larr; M[B] AND M[C]                       |2           |
+//! |`or A B C`        |M[A] &larr; M[B] OR M[C]                        |4           |
+//! |`call A`          |M[A &plus; 1] &larr; return address, PC &larr; A|5           |
+//! |`ret A`           |PC &larr; M[A &plus; 1]                         |4           |
 
 use std::vec::Vec;
 use std::string::{self, String};
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::error;
+use std::fmt;
 use std::io::{self, Write};
+use std::fs;
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use super::object::{self, Object, Relocation};
+
+// Opcode abbreviations, indexed by opcode.
+const MNEMONICS: [&str; 18] = ["brk", "lpc", "beq", "spc", "lea", "lra", "sra", "add", "sub",
+                               "mul", "div", "mod", "cmp", "nad", "drw", "key", "nop", "cfv"];
+
+/// Returns the three letter abbreviation for `opcode`.
+///
+/// # Example
+///
+/// ```
+/// use chifir::compiler;
+///
+/// assert_eq!(Some("lpc"), compiler::mnemonic(1));
+/// assert_eq!(None, compiler::mnemonic(18));
+/// ```
+pub fn mnemonic(opcode: u32) -> Option<&'static str> {
+    MNEMONICS.get(opcode as usize).cloned()
+}
 
 pub struct Compiler {
     assembly: Vec<u8>,
+    // The file the assembly was read from, if any.
+    path: Option<PathBuf>,
+    include_dirs: Vec<PathBuf>,
     lines: Vec<String>,
-    instructions: Vec<String>,
+    instructions: Vec<Instruction>,
     labels: HashMap<String, u32>,
+    // The instruction before and the address of every definition of each
+    // numeric label, in order.
+    numeric: HashMap<String, Vec<(usize, u32)>>,
+    // The instruction defining every `.equ` constant and its address.
+    definitions: HashMap<String, (usize, u32)>,
+    constants: HashMap<String, u32>,
+    relocatable: bool,
+    // The labels exported with `.global`.
+    exports: Vec<String>,
+    // The value every `.extern` symbol stands for in the current pass.
+    externs: HashMap<String, u32>,
+    // How far every label is moved in the current pass.
+    base: u32,
     bytecodes: Vec<u32>,
+    // The instruction and the token every bytecode came from.
+    sources: Vec<(usize, usize)>,
+    object: Option<Object>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// Pseudo-instructions, the number of operands they take and the real
+// instructions they expand into. `$1` to `$3` stand for the operands.
+// Relative operands count from the first word of the expansion, which lets
+// expansions keep scratch words in the operands of a trailing `nop`.
+const PSEUDO_INSTRUCTIONS: [(&str, usize, &[&str]); 9] = [
+    ("jmp", 1, &["lpc /0x2 $1"]),
+    ("jeq", 2, &["beq /0x3 $1 $2"]),
+    ("jne", 2, &["beq /0x3 $1 /0x8", "lpc /0x6 $2"]),
+    ("mov", 2, &["lea $1 $2"]),
+    ("not", 2, &["nad $1 $2 $2"]),
+    ("and", 3, &["nad $1 $2 $3", "nad $1 $1 $1"]),
+    ("or", 3, &["nad /0xd $2 $2", "nad /0xe $3 $3", "nad $1 /0xd /0xe", "nop"]),
+    // The return address is kept in the A operand of the routine's first
+    // instruction, so `call` computes its address, stores the address after
+    // the expansion there and jumps to the routine.
+    ("call",
+     1,
+     &["add /0xd /0xe /0xf", "sra /0x11 /0xd", "lpc /0xe", "nop 0 $1 1", "nop /0x14"]),
+    ("ret", 1, &["add /0xd /0xe /0xf", "lra /0xd /0xd", "lpc /0xd", "nop 0 $1 1"]),
+];
+
+// A line of assembly without its comment, along with where it starts in the
+// source.
+struct Instruction {
+    text: String,
+    // The whole line the instruction is on, comment included.
+    source: String,
+    file: Option<PathBuf>,
+    line: usize,
+    column: usize,
+    // The whitespace separated tokens of `text` along with their columns.
+    tokens: Vec<(usize, String)>,
+    // How many words into the expansion of a pseudo-instruction this
+    // instruction is.
+    offset: u32,
+    // The macro calls this instruction came from, innermost first.
+    expansions: Vec<Expansion>,
+    // The radix of numbers without a prefix.
+    radix: u32,
+    // The last global label before this instruction, which local labels
+    // belong to.
+    scope: String,
+}
+
+// A macro definition, with the instructions between `.macro` and `.endm`.
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Instruction>,
+}
+
+// Macros expanding into more macros deeper than this are taken to be
+// recursive.
+const MACRO_DEPTH: usize = 64;
+
+// How far labels and external symbols are moved to find the words holding
+// addresses. It's odd, so no multiple of an address moves by exactly this
+// much other than the address itself.
+const SHIFT: u32 = 0x9e37_79b9;
+
+impl Instruction {
+    fn new(text: &str, line: usize, column: usize) -> Self {
+        let mut tokens = Vec::new();
+        let mut start = None;
+
+        // Whitespace inside quotes belongs to the token.
+        for (index, (_, c, quoted)) in quoting(text).into_iter().enumerate() {
+            match (c.is_whitespace() && !quoted, start.take()) {
+                (true, Some(token)) => tokens.push(token),
+                (false, None) => start = Some((column + index, c.to_string())),
+                (false, Some((column, mut token))) => {
+                    token.push(c);
+                    start = Some((column, token));
+                }
+                (true, None) => {}
+            }
+        }
+        tokens.extend(start);
+
+        Instruction {
+            text: text.to_string(),
+            source: String::new(),
+            file: None,
+            line,
+            column,
+            tokens,
+            offset: 0,
+            expansions: Vec::new(),
+            radix: 16,
+            scope: String::new(),
+        }
+    }
+
+    // Returns a copy of the instruction with different tokens.
+    fn with_tokens(&self, tokens: Vec<(usize, String)>) -> Self {
+        let text: Vec<&str> = tokens.iter().map(|(_, token)| token.as_str()).collect();
+
+        Instruction {
+            text: text.join(" "),
+            source: self.source.clone(),
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+            tokens,
+            offset: self.offset,
+            expansions: self.expansions.clone(),
+            radix: self.radix,
+            scope: self.scope.clone(),
+        }
+    }
+
+    // Returns the label, if this is one.
+    fn label(&self) -> Option<&str> {
+        find_unquoted(&self.text, ':').map(|index| &self.text[..index])
+    }
+
+    // Returns the first token, unless this is a label.
+    fn mnemonic(&self) -> Option<&str> {
+        match self.tokens.first() {
+            Some((_, mnemonic)) if self.label().is_none() => Some(mnemonic.as_str()),
+            _ => None,
+        }
+    }
+
+    // Returns the directive, if this is one.
+    fn directive(&self) -> Option<&str> {
+        self.mnemonic().filter(|mnemonic| mnemonic.starts_with('.'))
+    }
+
+    // Returns how many words this compiles to when it starts at `address`.
+    fn words(&self, address: u32) -> Result<u32, Diagnostic> {
+        let directive = match self.directive() {
+            Some(directive) => directive,
+            None => return Ok(4),
+        };
+        let operands = self.tokens.len() - 1;
+        let error = |index: usize, message: String| {
+            let (column, ref token) = self.tokens[index];
+            self.diagnostic(Severity::Error, column, token, message)
+        };
+
+        let expected = match directive {
+            ".word" if operands == 0 => {
+                return Err(error(0, "`.word` expects at least 1 operand".to_string()))
+            }
+            ".word" => return Ok(operands as u32),
+            ".global" | ".extern" if operands == 0 => {
+                return Err(error(0, format!("`{}` expects at least 1 operand", directive)))
+            }
+            ".global" | ".extern" => return Ok(0),
+            ".fill" => 2,
+            ".zero" | ".align" | ".org" => 1,
+            ".ascii" | ".asciz" | ".pack" | ".packz" => 1,
+            ".equ" => 2,
+            _ => return Err(error(0, format!("unknown directive `{}`", directive))),
+        };
+        if operands != expected {
+            return Err(error(0, expects(directive, expected, operands)));
+        }
+
+        if directive == ".equ" {
+            return Ok(0);
+        }
+
+        if is_string(directive) {
+            let string = encode_string(directive, &self.tokens[1].1).map_err(|message| error(1, message))?;
+            return Ok(string.len() as u32);
+        }
+
+        let number = parse_number(&self.tokens[1].1, self.radix).map_err(|message| error(1, message))?;
+        let words = match directive {
+            ".align" if number == 0 => return Err(error(1, "can't align to 0 words".to_string())),
+            ".align" => (number - address % number) % number,
+            ".org" if number < address => {
+                let message = format!("`.org` can't go back from {:x} to {:x}", address, number);
+                return Err(error(1, message));
+            }
+            ".org" => number - address,
+            _ => number,
+        };
+
+        match address.checked_add(words) {
+            Some(_) => Ok(words),
+            None => Err(error(1, "the program doesn't fit in memory".to_string())),
+        }
+    }
+
+    fn diagnostic(&self, severity: Severity, column: usize, token: &str, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            file: self.file.clone(),
+            line: self.line,
+            column,
+            token: token.to_string(),
+            message,
+            expansions: self.expansions.clone(),
+        }
+    }
 }
 
 impl Compiler {
     pub fn new() -> Self {
         Compiler {
             assembly: Vec::new(),
+            path: None,
+            include_dirs: Vec::new(),
             lines: Vec::new(),
             instructions: Vec::new(),
             labels: HashMap::new(),
+            numeric: HashMap::new(),
+            definitions: HashMap::new(),
+            constants: HashMap::new(),
+            relocatable: false,
+            exports: Vec::new(),
+            externs: HashMap::new(),
+            base: 0,
             bytecodes: Vec::new(),
+            sources: Vec::new(),
+            object: None,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Returns the address of every label found by the last `compile`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use chifir::compiler::Compiler;
+    ///
+    /// let mut compiler = Compiler::new();
+    ///
+    /// write!(compiler, "{}","
+    /// start:
+    ///   nop
+    /// end:
+    ///   brk
+    /// ").unwrap();
+    ///
+    /// compiler.compile().unwrap();
+    ///
+    /// assert_eq!(Some(&0x4), compiler.labels().get("end"));
+    /// ```
+    pub fn labels(&self) -> &HashMap<String, u32> {
+        &self.labels
+    }
+
+    /// Returns the value of every `.equ` constant found by the last
+    /// `compile`.
+    pub fn constants(&self) -> &HashMap<String, u32> {
+        &self.constants
+    }
+
+    /// Returns every line compiled by the last `compile` that defines a
+    /// label or emits words, along with its address and words. Lines
+    /// expanding into several instructions are listed once.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use chifir::compiler::Compiler;
+    ///
+    /// let mut compiler = Compiler::new();
+    ///
+    /// write!(compiler, "{}","
+    /// start:
+    ///   jmp start  ; Forever
+    /// ").unwrap();
+    ///
+    /// compiler.compile().unwrap();
+    /// let listing: Vec<String> = compiler.listing().iter().map(|line| line.to_string()).collect();
+    ///
+    /// assert_eq!(vec![
+    ///     "00000000                                       2  start:",
+    ///     "00000000  00000001 00000002 00000000 00000000  3    jmp start  ; Forever",
+    /// ], listing);
+    /// ```
+    pub fn listing(&self) -> Vec<ListingLine> {
+        let mut listing: Vec<ListingLine> = Vec::new();
+        let mut previous: Option<&Instruction> = None;
+        let mut address = 0;
+
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            let start = address;
+            while self.sources.get(address).is_some_and(|&(source, _)| source == index) {
+                address += 1;
+            }
+            let words = &self.bytecodes[start..address];
+
+            let same_line = previous.is_some_and(|previous| {
+                (&previous.file, previous.line, &previous.expansions) ==
+                (&instruction.file, instruction.line, &instruction.expansions)
+            });
+            previous = Some(instruction);
+
+            match listing.last_mut() {
+                Some(last) if same_line => last.words.extend_from_slice(words),
+                _ => {
+                    listing.push(ListingLine {
+                        address: start as u32,
+                        words: words.to_vec(),
+                        file: instruction.file.clone(),
+                        line: instruction.line,
+                        text: instruction.source.clone(),
+                    })
+                }
+            }
         }
+
+        listing
+    }
+
+    /// Returns every label found by the last `compile` in order of address,
+    /// one `address name` pair to a line.
+    pub fn symbol_map(&self) -> String {
+        let mut labels: Vec<(&u32, &String)> = self.labels.iter().map(|(name, address)| (address, name)).collect();
+        labels.sort();
+
+        labels.into_iter().map(|(address, name)| format!("{:08x} {}\n", address, name)).collect()
+    }
+
+    /// Returns every problem found by the last `compile`, in source order.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        self.diagnostics.as_slice()
+    }
+
+    /// Adds a directory to look in for the files named by `.include`, after
+    /// the directory of the file including them.
+    pub fn include_dir<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.include_dirs.push(directory.into());
+        self
+    }
+
+    /// Compiles programs into relocatable objects as well, which can use
+    /// symbols from other objects with `.extern`.
+    pub fn relocatable(mut self) -> Self {
+        self.relocatable = true;
+        self
+    }
+
+    /// Returns the object compiled by the last `compile`, if the compiler is
+    /// `relocatable` and the program compiled.
+    pub fn object(&self) -> Option<&Object> {
+        self.object.as_ref()
+    }
+
+    /// Compiles the assembly in the file at `path`, instead of anything
+    /// written to the compiler. Diagnostics name the file they're in.
+    pub fn compile_file<P: AsRef<Path>>(&mut self, path: P) -> Result<&[u32], CompilerError> {
+        let path = path.as_ref().to_path_buf();
+        self.assembly = fs::read(&path).map_err(|error| CompilerError::Io(path.clone(), error))?;
+        self.path = Some(path);
+        self.compile()
     }
 
     pub fn compile(&mut self) -> Result<&[u32], CompilerError> {
-        let assembly = try!(String::from_utf8(self.assembly.to_vec())
-            .map_err(CompilerError::FromUtf8Error));
-        self.split_lines(assembly.as_str());
-        self.strip_comments();
+        self.lines.clear();
+        self.instructions.clear();
+        self.labels.clear();
+        self.numeric.clear();
+        self.definitions.clear();
+        self.constants.clear();
+        self.exports.clear();
+        self.externs.clear();
+        self.bytecodes.clear();
+        self.sources.clear();
+        self.object = None;
+        self.diagnostics.clear();
+
+        let assembly = String::from_utf8(self.assembly.to_vec())
+            .map_err(CompilerError::FromUtf8Error)?;
+        self.lines = split_lines(assembly.as_str());
+        let instructions = strip_comments(&self.lines, &self.path);
+        let mut including = self.path.iter().map(|path| canonicalize(path)).collect();
+        self.expand_includes(instructions, &mut including);
+        self.expand_macros();
+        self.apply_radixes();
+        self.scope_labels();
+        self.expand_pseudo_instructions();
         self.compile_labels();
+        self.compile_symbols();
+        self.compile_constants();
         self.compile_bytecodes();
+        if self.relocatable && self.diagnostics.iter().all(|diagnostic| diagnostic.severity != Severity::Error) {
+            self.compile_object();
+        }
+
+        self.diagnostics.sort_by(|a, b| (&a.file, a.line, a.column).cmp(&(&b.file, b.line, b.column)));
+        if self.diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error) {
+            return Err(CompilerError::Diagnostics(self.diagnostics.clone()));
+        }
+
         Ok(self.bytecodes.as_slice())
     }
 
-    // Transform an opcode into a bytecode. Undefined opcodes, or thoses that
-    // fail to parse, are treated as zero. This maintains the concept of all
-    // uninitialized memory being zeroed out.
-    fn parse_opcode(&self, opcode: Option<&str>) -> u32 {
-        match opcode {
-            Some("brk") => 0,
-            Some("lpc") => 1,
-            Some("beq") => 2,
-            Some("spc") => 3,
-            Some("lea") => 4,
-            Some("lra") => 5,
-            Some("sra") => 6,
-            Some("add") => 7,
-            Some("sub") => 8,
-            Some("mul") => 9,
-            Some("div") => 10,
-            Some("mod") => 11,
-            Some("cmp") => 12,
-            Some("nad") => 13,
-            Some("drw") => 14,
-            Some("key") => 15,
-            Some("nop") => 16,
-            Some("cfv") => 17,
-            Some(opcode) => u32::from_str_radix(opcode, 16).unwrap_or(0),
-            None => 0,
-        }
-    }
-
-    // Transform an operand into a bytecode. Undefined operands, or thoses that
-    // fail to parse, are treated as zero. This maintains the concept of all
-    // uninitialized memory being zeroed out.
-    fn parse_operand(&self, operand: Option<&str>, opcode_address: u32) -> u32 {
-        match operand {
-            Some(operand) => {
-                match self.labels.get(operand) {
-                    // Operand is a label with an absolute address
-                    Some(address) => *address,
-
-                    None => {
-                        // Operand is a relative address
-                        if operand.starts_with('/') {
-                            let address = operand.trim_left_matches('/');
-                            let address = u32::from_str_radix(address, 16).unwrap_or(0);
-                            address + opcode_address
-                        }
-                        // Operand is a numeric value
-                        else {
-                            u32::from_str_radix(operand, 16).unwrap_or(0)
-                        }
+    // Transform an opcode into a bytecode, either from its abbreviation or
+    // from a number.
+    fn parse_opcode(&self, opcode: &str, radix: u32) -> Result<u32, String> {
+        match MNEMONICS.iter().position(|mnemonic| *mnemonic == opcode) {
+            Some(index) => Ok(index as u32),
+            None => {
+                parse_number(opcode, radix).map_err(|error| {
+                    if opcode.starts_with(|c: char| c.is_ascii_digit() || c == '#') {
+                        error
+                    } else {
+                        format!("unknown mnemonic `{}`", opcode)
                     }
-                }
+                })
+            }
+        }
+    }
+
+    // Transform an operand into a bytecode. Operands are expressions of
+    // labels, constants, characters, numbers and addresses relative to the
+    // opcode with a leading `/`.
+    fn parse_operand(&self, operand: &str, origin: u32, index: usize) -> Result<u32, String> {
+        let mut symbol = |name: &str| self.symbol(name, index);
+        evaluate(operand, origin.wrapping_add(self.base), self.instructions[index].radix, &mut symbol)
+    }
+
+    // Looks up a label, an evaluated constant or an external symbol as seen
+    // from the instruction at `index`.
+    fn symbol(&self, name: &str, index: usize) -> Result<Option<u32>, String> {
+        if let Some(address) = self.labels.get(name) {
+            return Ok(Some(address.wrapping_add(self.base)));
+        }
+        if let Some(value) = self.constants.get(name).or_else(|| self.externs.get(name)) {
+            return Ok(Some(*value));
+        }
+
+        if name.starts_with('.') {
+            let scoped = format!("{}{}", self.instructions[index].scope, name);
+            return Ok(self.labels.get(&scoped).map(|address| address.wrapping_add(self.base)));
+        }
+
+        // `@1b` is the closest `1:` before the instruction and `@1f` the
+        // closest after it.
+        let reference = match name.strip_prefix('@') {
+            Some(reference) => reference,
+            None => return Ok(None),
+        };
+        let (number, backwards) = match (reference.strip_suffix('b'), reference.strip_suffix('f')) {
+            (Some(number), _) if is_numeric(number) => (number, true),
+            (_, Some(number)) if is_numeric(number) => (number, false),
+            _ => return Ok(None),
+        };
+
+        let definitions = self.numeric.get(number).map(Vec::as_slice).unwrap_or_default();
+        let found = if backwards {
+            definitions.iter().rev().find(|&&(definition, _)| definition <= index)
+        } else {
+            definitions.iter().find(|&&(definition, _)| definition > index)
+        };
+
+        match found {
+            Some(&(_, address)) => Ok(Some(address.wrapping_add(self.base))),
+            None => {
+                Err(format!("no `{}:` label {} this line",
+                            number,
+                            if backwards { "before" } else { "after" }))
             }
-            None => 0,
         }
     }
 
     fn compile_bytecodes(&mut self) {
-        let mut instructions = self.instructions.iter();
+        let mut diagnostics = Vec::new();
 
-        while let Some(instruction) = instructions.next() {
-            match instruction.find(':') {
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            match instruction.label() {
                 Some(_) => {
                     // Ignore labels
                 }
-                None => {
-                    let opcode_address = self.bytecodes.len() as u32;
-                    let mut bytecodes = instruction.split_whitespace();
-
-                    // Opcode
-                    let opcode = bytecodes.next();
-                    let opcode = self.parse_opcode(opcode);
-                    self.bytecodes.push(opcode);
+                None if instruction.directive().is_some() => {
+                    // Relative operands count from the first word of the
+                    // directive. Problems with the size were reported along
+                    // with the labels.
+                    let origin = self.bytecodes.len() as u32;
+                    let words = instruction.words(origin).unwrap_or(0);
+                    let values = match instruction.directive() {
+                        Some(".word") => &instruction.tokens[1..],
+                        Some(".fill") => &instruction.tokens[2..],
+                        _ => &[],
+                    };
+
+                    if let Some(directive) = instruction.directive() {
+                        if is_string(directive) {
+                            if let [(_, ref token)] = instruction.tokens[1..] {
+                                self.bytecodes.extend(encode_string(directive, token).unwrap_or_default());
+                            }
+                            self.sources.resize(self.bytecodes.len(), (index, 0));
+                            continue;
+                        }
+                    }
 
-                    // Operand A
-                    let operand_a = bytecodes.next();
-                    let operand_a = self.parse_operand(operand_a, opcode_address);
-                    self.bytecodes.push(operand_a);
+                    let mut bytecodes = Vec::new();
+                    for &(column, ref token) in values {
+                        let bytecode = self.parse_operand(token, origin, index).unwrap_or_else(|message| {
+                            diagnostics.push(instruction.diagnostic(Severity::Error,
+                                                                    column,
+                                                                    token,
+                                                                    message));
+                            0
+                        });
+                        bytecodes.push(bytecode);
+                    }
 
-                    // Operand B
-                    let operand_b = bytecodes.next();
-                    let operand_b = self.parse_operand(operand_b, opcode_address);
-                    self.bytecodes.push(operand_b);
+                    if instruction.directive() == Some(".word") {
+                        self.sources.extend((0..bytecodes.len()).map(|position| (index, position + 1)));
+                        self.bytecodes.extend(bytecodes);
+                    } else {
+                        let value = bytecodes.first().cloned().unwrap_or(0);
+                        let position = if values.is_empty() { 0 } else { 2 };
+                        self.sources.extend((0..words).map(|_| (index, position)));
+                        self.bytecodes.extend((0..words).map(|_| value));
+                    }
+                }
+                None => {
+                    // Relative operands count from the opcode, or from the
+                    // start of a pseudo-instruction.
+                    let origin = self.bytecodes.len() as u32 - instruction.offset;
+                    let tokens = &instruction.tokens;
+
+                    // Missing operands are treated as zero. This maintains
+                    // the concept of all uninitialized memory being zeroed
+                    // out.
+                    for position in 0..4 {
+                        let bytecode = match tokens.get(position) {
+                            Some(&(column, ref token)) => {
+                                let bytecode = if position == 0 {
+                                    self.parse_opcode(token, instruction.radix)
+                                } else {
+                                    self.parse_operand(token, origin, index)
+                                };
+
+                                bytecode.unwrap_or_else(|message| {
+                                    diagnostics.push(instruction.diagnostic(Severity::Error,
+                                                                            column,
+                                                                            token,
+                                                                            message));
+                                    0
+                                })
+                            }
+                            None => 0,
+                        };
+                        self.bytecodes.push(bytecode);
+                        self.sources.push((index, position));
+                    }
 
-                    // Operand C
-                    let operand_c = bytecodes.next();
-                    let operand_c = self.parse_operand(operand_c, opcode_address);
-                    self.bytecodes.push(operand_c);
+                    if let Some(&(column, ref token)) = tokens.get(4) {
+                        diagnostics.push(instruction.diagnostic(Severity::Error,
+                                                                column,
+                                                                token,
+                                                                "too many operands, expected at \
+                                                                 most 3"
+                                                                    .to_string()));
+                    }
                 }
             }
         }
+
+        self.diagnostics.extend(diagnostics);
     }
 
     fn compile_labels(&mut self) {
-        let mut instructions = self.instructions.iter();
         let mut address = 0;
+        let mut lines = HashMap::new();
+
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            match instruction.label() {
+                Some(label) if is_numeric(label) => {
+                    self.numeric.entry(label.to_string()).or_default().push((index, address));
+                }
+                Some(label) => {
+                    if let Some(line) = lines.insert(label.to_string(), instruction.line) {
+                        let message = format!("label `{}` is already defined on line {}",
+                                              label,
+                                              line);
+                        self.diagnostics.push(instruction.diagnostic(Severity::Error,
+                                                                     instruction.column,
+                                                                     label,
+                                                                     message));
+                    }
+                    self.labels.insert(label.to_string(), address);
+                }
+                None => {
+                    match instruction.words(address) {
+                        Ok(words) => address += words,
+                        Err(diagnostic) => self.diagnostics.push(diagnostic),
+                    }
+
+                    if instruction.directive() == Some(".equ") && instruction.tokens.len() == 3 {
+                        let (column, ref name) = instruction.tokens[1];
+                        if let Some((previous, _)) = self.definitions.insert(name.clone(), (index, address)) {
+                            let message = format!("constant `{}` is already defined on line {}",
+                                                  name,
+                                                  self.instructions[previous].line);
+                            self.diagnostics.push(instruction.diagnostic(Severity::Error,
+                                                                         column,
+                                                                         name,
+                                                                         message));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Collects the labels exported with `.global` and the symbols imported
+    // with `.extern`.
+    fn compile_symbols(&mut self) {
+        let mut diagnostics = Vec::new();
+
+        for instruction in self.instructions.iter() {
+            let directive = match instruction.directive() {
+                Some(directive) if directive == ".global" || directive == ".extern" => directive,
+                _ => continue,
+            };
+            if directive == ".extern" && !self.relocatable {
+                let (column, ref token) = instruction.tokens[0];
+                diagnostics.push(instruction.diagnostic(Severity::Error,
+                                                        column,
+                                                        token,
+                                                        "`.extern` only works in relocatable \
+                                                         objects"
+                                                            .to_string()));
+            }
+
+            for &(column, ref name) in &instruction.tokens[1..] {
+                let defined = self.labels.contains_key(name) || self.definitions.contains_key(name);
+                let message = match directive {
+                    ".extern" if defined => format!("`{}` is defined here, so it can't be external", name),
+                    ".extern" => {
+                        self.externs.insert(name.clone(), 0);
+                        continue;
+                    }
+                    _ if self.labels.contains_key(name) => {
+                        if !self.exports.contains(name) {
+                            self.exports.push(name.clone());
+                        }
+                        continue;
+                    }
+                    _ if defined => format!("constant `{}` can't be exported, only labels can", name),
+                    _ => format!("undefined label `{}`", name),
+                };
+                diagnostics.push(instruction.diagnostic(Severity::Error, column, name, message));
+            }
+        }
+
+        self.diagnostics.extend(diagnostics);
+    }
+
+    // Finds the words holding addresses by compiling the program again with
+    // every label moved by `SHIFT`, and then once for every external symbol
+    // with just that symbol at `SHIFT`. Words that move by exactly `SHIFT`
+    // hold an address plus or minus a number and get a relocation.
+    fn compile_object(&mut self) {
+        let bytecodes = mem::take(&mut self.bytecodes);
+        let constants = mem::take(&mut self.constants);
+        let sources = mem::take(&mut self.sources);
+
+        let mut externs: Vec<String> = self.externs.keys().cloned().collect();
+        externs.sort();
+        let mut targets = vec![None];
+        targets.extend(externs.into_iter().map(Some));
+
+        let mut moved = Vec::new();
+        for target in targets.iter() {
+            match *target {
+                Some(ref name) => {
+                    self.externs.insert(name.clone(), SHIFT);
+                }
+                None => self.base = SHIFT,
+            }
+
+            // Anything wrong was already reported by the first pass.
+            let count = self.diagnostics.len();
+            self.compile_constants();
+            self.compile_bytecodes();
+            self.diagnostics.truncate(count);
+            moved.push(mem::take(&mut self.bytecodes));
+
+            self.constants.clear();
+            self.sources.clear();
+            match *target {
+                Some(ref name) => {
+                    self.externs.insert(name.clone(), 0);
+                }
+                None => self.base = 0,
+            }
+        }
+
+        self.bytecodes = bytecodes;
+        self.constants = constants;
+        self.sources = sources;
+
+        let mut object = Object::default();
+        for (address, bytecode) in self.bytecodes.iter().enumerate() {
+            let shifts: Vec<(&Option<String>, u32)> = targets.iter()
+                .zip(moved.iter())
+                .map(|(target, moved)| (target, moved[address].wrapping_sub(*bytecode)))
+                .filter(|&(_, shift)| shift != 0)
+                .collect();
+
+            match shifts[..] {
+                [] => {}
+                [(symbol, SHIFT)] => {
+                    if let Some(ref name) = *symbol {
+                        if !object.imports.contains(name) {
+                            object.imports.push(name.clone());
+                        }
+                    }
+                    object.relocations.push(Relocation {
+                        address: address as u32,
+                        symbol: symbol.clone(),
+                    });
+                }
+                _ => {
+                    let (index, position) = self.sources[address];
+                    let instruction = &self.instructions[index];
+                    let (column, ref token) = instruction.tokens[position];
+                    let message = format!("`{}` isn't an address plus or minus a number, so it \
+                                           can't be relocated",
+                                          token);
+                    self.diagnostics.push(instruction.diagnostic(Severity::Error, column, token, message));
+                }
+            }
+        }
+
+        // Every `.align` counts from the start of the object, so the object
+        // has to start at a multiple of all of them.
+        for instruction in &self.instructions {
+            if instruction.directive() != Some(".align") {
+                continue;
+            }
+            let (column, ref token) = instruction.tokens[1];
+            let words = match parse_number(token, instruction.radix) {
+                Ok(words) => words,
+                Err(_) => continue,
+            };
+            match object::lcm(object.align, words) {
+                Some(align) => object.align = align,
+                None => {
+                    let message = format!("can't align the object to {:x} words as well as {:x}",
+                                          words,
+                                          object.align);
+                    self.diagnostics.push(instruction.diagnostic(Severity::Error, column, token, message));
+                }
+            }
+        }
+
+        object.code = self.bytecodes.clone();
+        object.exports = self.exports.iter().map(|name| (name.clone(), self.labels[name])).collect();
+        self.object = Some(object);
+    }
+
+    // Evaluates every `.equ` constant. Constants can use labels and each
+    // other, in any order.
+    fn compile_constants(&mut self) {
+        let mut names: Vec<String> = self.definitions.keys().cloned().collect();
+        names.sort();
+
+        for name in names {
+            self.constant(&name, &mut Vec::new());
+        }
+    }
+
+    // Returns the value of the constant `name`, evaluating it first if it
+    // hasn't been yet. `pending` holds the constants being evaluated, to
+    // catch constants defined in terms of themselves.
+    fn constant(&mut self, name: &str, pending: &mut Vec<String>) -> Option<u32> {
+        if let Some(value) = self.constants.get(name) {
+            return Some(*value);
+        }
+        let (index, origin) = *self.definitions.get(name)?;
+
+        let (column, token) = self.instructions[index].tokens[1].clone();
+        let result = if self.labels.contains_key(name) {
+            Err(format!("`{}` is already a label", name))
+        } else if pending.iter().any(|constant| constant == name) {
+            Err(format!("constant `{}` is defined in terms of itself", name))
+        } else {
+            pending.push(name.to_string());
+            let (_, ref expression) = self.instructions[index].tokens[2];
+            let expression = expression.clone();
+            let radix = self.instructions[index].radix;
+            let origin = origin.wrapping_add(self.base);
+            let result = {
+                let mut symbol = |symbol: &str| match self.symbol(symbol, index)? {
+                    Some(value) => Ok(Some(value)),
+                    None => Ok(self.constant(symbol, pending)),
+                };
+                evaluate(&expression, origin, radix, &mut symbol)
+            };
+            pending.pop();
+            result
+        };
+
+        // Constants that can't be evaluated are still defined, so their
+        // problem is only reported once.
+        let value = result.unwrap_or_else(|message| {
+            let diagnostic = self.instructions[index].diagnostic(Severity::Error, column, &token, message);
+            self.diagnostics.push(diagnostic);
+            0
+        });
+        self.constants.insert(name.to_string(), value);
+        Some(value)
+    }
+
+    // Replaces every `.include` with the instructions in the file it names.
+    // `including` holds the files being read, to catch files that include
+    // themselves.
+    fn expand_includes(&mut self, instructions: Vec<Instruction>, including: &mut Vec<PathBuf>) {
+        for instruction in instructions {
+            if instruction.directive() != Some(".include") {
+                self.instructions.push(instruction);
+                continue;
+            }
+
+            let operands = instruction.tokens.len() - 1;
+            if operands != 1 {
+                self.error(&instruction, 0, &expects(".include", 1, operands));
+                continue;
+            }
 
-        while let Some(instruction) = instructions.next() {
-            match instruction.find(':') {
-                Some(index) => {
-                    self.labels.insert(instruction.split_at(index).0.to_string(), address);
+            let name = match unquote(&instruction.tokens[1].1, '"') {
+                Ok(name) => String::from_utf8_lossy(&name).into_owned(),
+                Err(message) => {
+                    self.error(&instruction, 1, &message);
+                    continue;
                 }
+            };
+
+            // Files are found next to the file including them first, or in the
+            // current directory for assembly that isn't in a file, then in the
+            // include directories.
+            let directory = instruction.file.as_ref().and_then(|file| file.parent());
+            let path = iter::once(directory.unwrap_or_else(|| Path::new("")))
+                .chain(self.include_dirs.iter().map(|directory| directory.as_path()))
+                .map(|directory| directory.join(&name))
+                .find(|path| path.is_file());
+
+            let path = match path {
+                Some(path) => path,
                 None => {
-                    address += 4;
+                    self.error(&instruction, 1, &format!("can't find `{}`", name));
+                    continue;
                 }
+            };
+
+            let canonical = canonicalize(&path);
+            if including.contains(&canonical) {
+                self.error(&instruction, 1, &format!("`{}` includes itself", name));
+                continue;
+            }
+
+            let assembly = fs::read(&path)
+                .map_err(|error| error.to_string())
+                .and_then(|assembly| String::from_utf8(assembly).map_err(|error| error.to_string()));
+            let assembly = match assembly {
+                Ok(assembly) => assembly,
+                Err(error) => {
+                    self.error(&instruction, 1, &format!("can't read `{}`: {}", name, error));
+                    continue;
+                }
+            };
+
+            let included = strip_comments(&split_lines(&assembly), &Some(path));
+            including.push(canonical);
+            self.expand_includes(included, including);
+            including.pop();
+        }
+    }
+
+    // Collects the macro definitions and replaces every macro call with the
+    // macro's body.
+    fn expand_macros(&mut self) {
+        let instructions = mem::take(&mut self.instructions);
+        let mut macros = HashMap::new();
+        let mut calls = Vec::new();
+        let mut definition: Option<(Instruction, String, Macro)> = None;
+
+        for instruction in instructions {
+            let mnemonic = instruction.mnemonic().map(|mnemonic| mnemonic.to_string());
+
+            match (mnemonic.as_deref(), definition.take()) {
+                (Some(".macro"), Some(open)) => {
+                    self.error(&instruction, 0, "macros can't be defined inside macros");
+                    definition = Some(open);
+                }
+                (Some(".macro"), None) => {
+                    let mut names = instruction.tokens[1..].iter().map(|(_, name)| name.clone());
+
+                    match names.next() {
+                        Some(name) => {
+                            let parameters = names.collect();
+                            definition = Some((instruction,
+                                               name,
+                                               Macro {
+                                                   parameters,
+                                                   body: Vec::new(),
+                                               }));
+                        }
+                        None => self.error(&instruction, 0, "`.macro` needs a name"),
+                    }
+                }
+                (Some(".endm"), Some((start, name, closed))) => {
+                    let taken = MNEMONICS.contains(&name.as_str()) ||
+                                PSEUDO_INSTRUCTIONS.iter().any(|pseudo| pseudo.0 == name);
+
+                    if taken {
+                        self.error(&start, 1, &format!("`{}` is already an instruction", name));
+                    } else {
+                        match macros.entry(name) {
+                            Entry::Occupied(entry) => {
+                                let message = format!("macro `{}` is already defined", entry.key());
+                                self.error(&start, 1, &message);
+                            }
+                            Entry::Vacant(entry) => {
+                                entry.insert(closed);
+                            }
+                        }
+                    }
+                }
+                (Some(".endm"), None) => self.error(&instruction, 0, "`.endm` without `.macro`"),
+                (_, Some((start, name, mut open))) => {
+                    open.body.push(instruction);
+                    definition = Some((start, name, open));
+                }
+                (_, None) => calls.push(instruction),
+            }
+        }
+
+        if let Some((start, _, _)) = definition {
+            self.error(&start, 0, "`.macro` without `.endm`");
+        }
+
+        let mut count = 0;
+        for instruction in calls {
+            self.expand_macro(instruction, &macros, &mut count);
+        }
+    }
+
+    // Adds `instruction` to the instructions, expanding it first if it's a
+    // macro call. `count` numbers the expansions, to keep their local labels
+    // apart.
+    fn expand_macro(&mut self, instruction: Instruction, macros: &HashMap<String, Macro>, count: &mut usize) {
+        let (name, definition) = match instruction.mnemonic().and_then(|name| macros.get_key_value(name)) {
+            Some(found) => found,
+            None => {
+                self.instructions.push(instruction);
+                return;
+            }
+        };
+
+        let arguments = &instruction.tokens[1..];
+        if arguments.len() != definition.parameters.len() {
+            let message = format!("macro {}", expects(name, definition.parameters.len(), arguments.len()));
+            self.error(&instruction, 0, &message);
+            return;
+        }
+        if instruction.expansions.len() >= MACRO_DEPTH {
+            self.error(&instruction, 0, &format!("macro `{}` never stops expanding", name));
+            return;
+        }
+
+        *count += 1;
+        let mut expansions = vec![Expansion {
+                                      name: name.clone(),
+                                      file: instruction.file.clone(),
+                                      line: instruction.line,
+                                      column: instruction.column,
+                                  }];
+        expansions.extend(instruction.expansions.iter().cloned());
+
+        for line in definition.body.iter() {
+            // Parameters are replaced by the arguments, and `%` labels get a
+            // name of their own in every expansion.
+            let tokens = line.tokens
+                .iter()
+                .map(|(column, token)| {
+                    let (label, colon) = match token.find(':') {
+                        Some(index) if line.mnemonic().is_none() => token.split_at(index),
+                        _ => (token.as_str(), ""),
+                    };
+
+                    let label = match definition.parameters.iter().position(|parameter| parameter == label) {
+                        Some(index) => arguments[index].1.clone(),
+                        None if label.starts_with('%') => format!("{}.{}", label, count),
+                        None => label.to_string(),
+                    };
+
+                    (*column, label + colon)
+                })
+                .collect();
+
+            let mut expanded = line.with_tokens(tokens);
+            expanded.expansions = expansions.clone();
+            self.expand_macro(expanded, macros, count);
+        }
+    }
+
+    // Reports an error with the token at `index` in `instruction`.
+    fn error(&mut self, instruction: &Instruction, index: usize, message: &str) {
+        let (column, token) = instruction.tokens[index].clone();
+        self.diagnostics.push(instruction.diagnostic(Severity::Error, column, &token, message.to_string()));
+    }
+
+    // Gives every instruction the radix set by the last `.radix` before it,
+    // and removes the `.radix` directives.
+    fn apply_radixes(&mut self) {
+        let instructions = mem::take(&mut self.instructions);
+        let mut radix = 16;
+
+        for mut instruction in instructions {
+            if instruction.directive() != Some(".radix") {
+                instruction.radix = radix;
+                self.instructions.push(instruction);
+                continue;
+            }
+
+            let operands = instruction.tokens.len() - 1;
+            if operands != 1 {
+                self.error(&instruction, 0, &expects(".radix", 1, operands));
+                continue;
+            }
+
+            // The new radix is always decimal, so `.radix 10` means the same
+            // thing whatever the radix was.
+            match parse_number(&instruction.tokens[1].1, 10) {
+                Ok(new) if [2, 8, 10, 16].contains(&new) => radix = new,
+                Ok(_) => self.error(&instruction, 1, "the radix must be 2, 8, 10 or 16"),
+                Err(message) => self.error(&instruction, 1, &message),
+            }
+        }
+    }
+
+    // Gives every instruction the scope of the last global label before it,
+    // and names local labels after their scope.
+    fn scope_labels(&mut self) {
+        let mut scope = String::new();
+
+        for instruction in self.instructions.iter_mut() {
+            let label = instruction.label().map(|label| label.to_string());
+
+            match label {
+                Some(ref label) if label.starts_with('.') => {
+                    instruction.text = format!("{}{}", scope, instruction.text);
+                    instruction.tokens[0].1 = format!("{}{}", scope, instruction.tokens[0].1);
+                }
+                // Macro and numeric labels don't start a scope.
+                Some(ref label) if label.starts_with('%') || is_numeric(label) => {}
+                Some(label) => scope = label,
+                None => {}
             }
+
+            instruction.scope = scope.clone();
         }
     }
 
-    fn strip_comments(&mut self) {
-        let mut lines = self.lines.iter();
-        while let Some(line) = lines.next() {
-            let trimmed_line = line.trim();
-            if !trimmed_line.is_empty() && !trimmed_line.starts_with(";") {
-                let instruction = match trimmed_line.find(';') {
-                    Some(index) => trimmed_line.split_at(index).0,
-                    None => trimmed_line,
-                };
-                self.instructions.push(instruction.trim().to_string());
-            }
-        }
+    // Replaces every pseudo-instruction with the real instructions it stands
+    // for.
+    fn expand_pseudo_instructions(&mut self) {
+        let instructions = mem::take(&mut self.instructions);
+
+        for instruction in instructions {
+            let pseudo = instruction.mnemonic().and_then(|mnemonic| {
+                PSEUDO_INSTRUCTIONS.iter().find(|pseudo| pseudo.0 == mnemonic)
+            });
+
+            let (mnemonic, count, expansion) = match pseudo {
+                Some(pseudo) => *pseudo,
+                _ => {
+                    self.instructions.push(instruction);
+                    continue;
+                }
+            };
+
+            let (column, _) = instruction.tokens[0];
+            let operands = &instruction.tokens[1..];
+            if operands.len() != count {
+                let message = expects(mnemonic, count, operands.len());
+                self.diagnostics.push(instruction.diagnostic(Severity::Error,
+                                                             column,
+                                                             mnemonic,
+                                                             message));
+                continue;
+            }
+
+            for (index, template) in expansion.iter().enumerate() {
+                let tokens: Vec<(usize, String)> = template.split(' ')
+                    .map(|token| {
+                        match token.strip_prefix('$') {
+                            Some(number) => operands[number.parse::<usize>().unwrap() - 1].clone(),
+                            None => (column, token.to_string()),
+                        }
+                    })
+                    .collect();
+                let mut expanded = instruction.with_tokens(tokens);
+                expanded.offset = index as u32 * 4;
+                self.instructions.push(expanded);
+            }
+        }
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Compiler::new()
+    }
+}
+
+impl Write for Compiler {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.assembly.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.assembly.flush()
+    }
+}
+
+// Returns the absolute path of `path` if there is one, which tells whether
+// two paths are the same file.
+fn canonicalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Turns every line of assembly that isn't blank or a comment into an
+// instruction from `file`.
+fn strip_comments(lines: &[String], file: &Option<PathBuf>) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        let trimmed_line = line.trim();
+        if !trimmed_line.is_empty() && !trimmed_line.starts_with(";") {
+            let instruction = match find_unquoted(trimmed_line, ';') {
+                Some(index) => trimmed_line.split_at(index).0,
+                None => trimmed_line,
+            };
+            let indent = line.chars().take_while(|c| c.is_whitespace()).count();
+
+            let mut instruction = Instruction::new(instruction.trim(), index + 1, indent + 1);
+            instruction.source = line.trim_end().to_string();
+            instruction.file = file.clone();
+            instructions.push(instruction);
+        }
+    }
+
+    instructions
+}
+
+fn split_lines(assembly: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut chars = assembly.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            // Line Feed | Vertical Tab | Form Feed | Next Line | Line/Paragraph Separator
+            '\u{000A}' | '\u{000B}' | '\u{000C}' | '\u{0085}' | '\u{2028}' | '\u{2029}' => {
+                lines.push(line);
+                line = String::new();
+            }
+
+            // Carriage Return
+            '\u{000D}' => {
+                lines.push(line);
+                line = String::new();
+
+                // Carriage Return + Line Feed
+                if let Some(n) = chars.next() {
+                    if n != '\u{000A}' {
+                        line.push(n);
+                    }
+                }
+            }
+
+            _ => {
+                line.push(c);
+            }
+        }
+    }
+
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
+
+// Describes the wrong number of operands being given to `name`.
+fn expects(name: &str, expected: usize, found: usize) -> String {
+    format!("`{}` expects {} operand{}, found {}",
+            name,
+            expected,
+            if expected == 1 { "" } else { "s" },
+            found)
+}
+
+// Pairs the byte index of every character in `text` with the character and
+// whether it's part of a quoted character or string, quotes included.
+fn quoting(text: &str) -> Vec<(usize, char, bool)> {
+    let mut quote = None;
+    let mut escaped = false;
+
+    text.char_indices()
+        .map(|(index, c)| {
+            let inside = quote.is_some();
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(_) if c == '\\' => escaped = true,
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if c == '\'' || c == '"' => quote = Some(c),
+                None => {}
+            }
+            (index, c, inside || quote.is_some())
+        })
+        .collect()
+}
+
+// Finds the first `target` in `text` that isn't quoted.
+fn find_unquoted(text: &str, target: char) -> Option<usize> {
+    quoting(text)
+        .into_iter()
+        .find(|&(_, c, quoted)| c == target && !quoted)
+        .map(|(index, _, _)| index)
+}
+
+// Returns the characters between the quotes of `token`, which start and end
+// with `quote`.
+fn unquote(token: &str, quote: char) -> Result<Vec<u8>, String> {
+    let unterminated = || format!("unterminated {}", if quote == '"' { "string" } else { "character" });
+    let inner = token.strip_prefix(quote).ok_or_else(unterminated)?;
+
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    loop {
+        let c = match chars.next() {
+            Some(c) if c == quote => break,
+            Some('\\') => {
+                match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('0') => '\0',
+                    Some(c) if c == '\\' || c == '\'' || c == '"' => c,
+                    Some(c) => return Err(format!("unknown escape `\\{}`", c)),
+                    None => return Err(unterminated()),
+                }
+            }
+            Some(c) => c,
+            None => return Err(unterminated()),
+        };
+
+        if !c.is_ascii() {
+            return Err(format!("`{}` isn't an ASCII character", c));
+        }
+        bytes.push(c as u8);
+    }
+
+    if chars.next().is_some() {
+        return Err(format!("unexpected characters after `{}`", quote));
+    }
+
+    Ok(bytes)
+}
+
+// Parses a quoted character into its ASCII code, the same byte `key` reads.
+fn parse_character(token: &str) -> Result<u32, String> {
+    match unquote(token, '\'')?[..] {
+        [byte] => Ok(byte as u32),
+        _ => Err(format!("`{}` isn't a single character", token)),
+    }
+}
+
+// Returns `true` for the directives that take a string.
+fn is_string(directive: &str) -> bool {
+    [".ascii", ".asciz", ".pack", ".packz"].contains(&directive)
+}
+
+// Lays out a quoted string in words for one of the string directives.
+// `.ascii` takes a word per character and `.pack` packs four characters into
+// every word, starting from the low byte. The `z` variants end the string
+// with a zero.
+fn encode_string(directive: &str, token: &str) -> Result<Vec<u32>, String> {
+    let mut bytes = unquote(token, '"')?;
+    if directive.ends_with('z') {
+        bytes.push(0);
+    }
+
+    if !directive.starts_with(".pack") {
+        return Ok(bytes.into_iter().map(|byte| byte as u32).collect());
+    }
+
+    Ok(bytes.chunks(4)
+        .map(|chunk| {
+            chunk.iter().enumerate().fold(0, |word, (index, &byte)| word | (byte as u32) << (index * 8))
+        })
+        .collect())
+}
+
+// Evaluates an operand expression. `origin` is what relative addresses
+// count from and `symbol` looks up labels and constants.
+fn evaluate(expression: &str,
+            origin: u32,
+            radix: u32,
+            symbol: &mut dyn FnMut(&str) -> Result<Option<u32>, String>)
+            -> Result<u32, String> {
+    // Labels can have any name, even one that looks like an expression.
+    if let Some(value) = symbol(expression)? {
+        return Ok(value);
+    }
+
+    let mut evaluator = Evaluator {
+        chars: expression.chars().collect(),
+        position: 0,
+        origin,
+        radix,
+        symbol,
+    };
+
+    let value = evaluator.sum()?;
+    match evaluator.peek() {
+        Some(c) => Err(format!("unexpected `{}`", c)),
+        None => Ok(value),
+    }
+}
+
+// A recursive descent parser for operand expressions that evaluates them as
+// it goes. Arithmetic wraps around like it does in the computer.
+struct Evaluator<'a> {
+    chars: Vec<char>,
+    position: usize,
+    origin: u32,
+    radix: u32,
+    symbol: &'a mut dyn FnMut(&str) -> Result<Option<u32>, String>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).cloned()
+    }
+
+    // Consumes characters while `include` accepts them and returns them.
+    fn take_while<F: Fn(char) -> bool>(&mut self, include: F) -> String {
+        let start = self.position;
+        while self.peek().is_some_and(&include) {
+            self.position += 1;
+        }
+        self.chars[start..self.position].iter().collect()
+    }
+
+    // sum = product (("+" | "-") product)*
+    fn sum(&mut self) -> Result<u32, String> {
+        let mut value = self.product()?;
+
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.position += 1;
+                    value = value.wrapping_add(self.product()?);
+                }
+                Some('-') => {
+                    self.position += 1;
+                    value = value.wrapping_sub(self.product()?);
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // product = unary (("*" | "/") unary)*
+    fn product(&mut self) -> Result<u32, String> {
+        let mut value = self.unary()?;
+
+        loop {
+            match self.peek() {
+                Some('*') => {
+                    self.position += 1;
+                    value = value.wrapping_mul(self.unary()?);
+                }
+                Some('/') => {
+                    self.position += 1;
+                    value = value.checked_div(self.unary()?).ok_or("division by zero")?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // unary = "-" unary | "/" unary | primary
+    fn unary(&mut self) -> Result<u32, String> {
+        match self.peek() {
+            Some('-') => {
+                self.position += 1;
+                Ok(self.unary()?.wrapping_neg())
+            }
+            // Relative addresses count from the origin.
+            Some('/') => {
+                self.position += 1;
+                Ok(self.unary()?.wrapping_add(self.origin))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    // primary = "(" sum ")" | number | character | name
+    fn primary(&mut self) -> Result<u32, String> {
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let value = self.sum()?;
+                match self.peek() {
+                    Some(')') => {
+                        self.position += 1;
+                        Ok(value)
+                    }
+                    _ => Err("expected `)`".to_string()),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '#' => {
+                self.position += 1;
+                let number = c.to_string() + &self.take_while(char::is_alphanumeric);
+                match (self.symbol)(&number)? {
+                    Some(value) => Ok(value),
+                    None => parse_number(&number, self.radix),
+                }
+            }
+            Some('\'') => {
+                let start = self.position;
+                let mut escaped = false;
+                self.position += 1;
+                while let Some(c) = self.peek() {
+                    self.position += 1;
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '\'' => break,
+                        _ => {}
+                    }
+                }
+                let character: String = self.chars[start..self.position].iter().collect();
+                parse_character(&character)
+            }
+            Some(c) if is_name(c) => self.name(),
+            Some(c) => Err(format!("unexpected `{}`", c)),
+            None => Err("expected a value".to_string()),
+        }
+    }
+
+    // Names can have dashes in them, so a dash is only a minus when the
+    // name before it is known.
+    fn name(&mut self) -> Result<u32, String> {
+        let start = self.position;
+        let run = self.take_while(is_name);
+
+        let ends = run.char_indices().filter(|&(_, c)| c == '-').map(|(index, _)| index);
+        let mut ends: Vec<usize> = ends.collect();
+        ends.push(run.len());
+
+        for &end in ends.iter().rev() {
+            if let Some(value) = (self.symbol)(&run[..end])? {
+                self.position = start + run[..end].chars().count();
+                return Ok(value);
+            }
+        }
+
+        // Otherwise it's a hex number that happens to start with a letter.
+        let first = &run[..ends[0]];
+        match parse_number(first, self.radix) {
+            Ok(value) => {
+                self.position = start + first.chars().count();
+                Ok(value)
+            }
+            Err(_) => Err(format!("undefined label `{}`", run)),
+        }
+    }
+}
+
+// Returns `true` for labels like `1`, which can be defined any number of
+// times.
+fn is_numeric(label: &str) -> bool {
+    !label.is_empty() && label.chars().all(|c| c.is_ascii_digit())
+}
+
+// Returns `true` for the characters of label and constant names, and of
+// numeric label references.
+fn is_name(c: char) -> bool {
+    c.is_alphanumeric() || "_-.%$@".contains(c)
+}
+
+// Parses a number in `radix`, unless a prefix says otherwise.
+fn parse_number(number: &str, radix: u32) -> Result<u32, String> {
+    let prefixed = |prefix: &str| number.strip_prefix(prefix).filter(|digits| !digits.is_empty());
+
+    let (digits, radix) = if let Some(digits) = prefixed("0x") {
+        (digits, 16)
+    } else if let Some(digits) = prefixed("0d").or_else(|| number.strip_prefix('#')) {
+        (digits, 10)
+    } else if let Some(digits) = prefixed("0o") {
+        (digits, 8)
+    } else if let Some(digits) = prefixed("0b") {
+        (digits, 2)
+    } else {
+        (number, radix)
+    };
+
+    match digits.chars().next() {
+        Some('+') | Some('-') => Err(format!("invalid number `{}`", number)),
+        _ => u32::from_str_radix(digits, radix).map_err(|_| format!("invalid number `{}`", number)),
     }
+}
 
-    fn split_lines(&mut self, assembly: &str) {
-        let mut line = String::new();
-        let mut chars = assembly.chars();
-
-        while let Some(c) = chars.next() {
-            match c {
-                // Line Feed | Vertical Tab | Form Feed | Next Line | Line/Paragraph Separator
-                '\u{000A}' | '\u{000B}' | '\u{000C}' | '\u{0085}' | '\u{2028}' | '\u{2029}' => {
-                    self.lines.push(line);
-                    line = String::new();
-                }
+/// How bad a `Diagnostic` is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The program can't be compiled.
+    Error,
+}
 
-                // Carriage Return
-                '\u{000D}' => {
-                    self.lines.push(line);
-                    line = String::new();
+/// A problem found while compiling, pointing at the token that caused it.
+///
+/// # Example
+///
+/// ```
+/// use std::io::Write;
+/// use chifir::compiler::{Compiler, CompilerError};
+///
+/// let mut compiler = Compiler::new();
+///
+/// write!(compiler, "{}", "
+/// loop:
+///   ky x
+///   lpc /2 lop
+/// ").unwrap();
+///
+/// match compiler.compile() {
+///     Err(CompilerError::Diagnostics(diagnostics)) => {
+///         let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+///
+///         assert_eq!(vec![
+///             "3:3: error: unknown mnemonic `ky`",
+///             "3:6: error: undefined label `x`",
+///             "4:10: error: undefined label `lop`",
+///         ], messages);
+///     }
+///     _ => panic!("expected diagnostics"),
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The file the problem is in, unless it's in assembly that was written
+    /// to the compiler.
+    pub file: Option<PathBuf>,
+    /// The line of the problem, counting from one.
+    pub line: usize,
+    /// The column of `token`, counting characters from one.
+    pub column: usize,
+    pub token: String,
+    pub message: String,
+    /// The macro calls that led to the problem, innermost first. Empty
+    /// unless the problem is inside a macro.
+    pub expansions: Vec<Expansion>,
+}
 
-                    // Carriage Return + Line Feed
-                    match chars.next() {
-                        Some(n) => {
-                            if n != '\u{000A}' {
-                                line.push(n);
-                            }
-                        }
-                        None => {}
-                    }
-                }
+/// A macro call, and where it was made.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expansion {
+    pub name: String,
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+}
 
-                _ => {
-                    line.push(c);
-                }
-            }
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+        };
+        write!(f,
+               "{}{}:{}: {}: {}",
+               location(&self.file),
+               self.line,
+               self.column,
+               severity,
+               self.message)?;
+
+        for expansion in self.expansions.iter() {
+            write!(f,
+                   "\n{}{}:{}: note: in expansion of macro `{}`",
+                   location(&expansion.file),
+                   expansion.line,
+                   expansion.column,
+                   expansion.name)?;
         }
 
-        if !line.is_empty() {
-            self.lines.push(line);
-        }
+        Ok(())
     }
 }
 
-impl Write for Compiler {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.assembly.write(buf)
+/// A line of a program along with where it was compiled to and the words it
+/// compiled to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListingLine {
+    pub address: u32,
+    pub words: Vec<u32>,
+    /// The file the line is in, unless it's in assembly that was written to
+    /// the compiler.
+    pub file: Option<PathBuf>,
+    /// The number of the line, counting from one.
+    pub line: usize,
+    /// The line, comment included.
+    pub text: String,
+}
+
+impl fmt::Display for ListingLine {
+    // Lines with more than four words go on for more rows, with the text
+    // next to the first one.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rows: Vec<&[u32]> = self.words.chunks(4).collect();
+        if rows.is_empty() {
+            rows.push(&[]);
+        }
+
+        for (row, words) in rows.into_iter().enumerate() {
+            let words: Vec<String> = words.iter().map(|word| format!("{:08x}", word)).collect();
+            let address = self.address.wrapping_add(row as u32 * 4);
+
+            if row == 0 {
+                write!(f,
+                       "{:08x}  {:<35}  {}{}  {}",
+                       address,
+                       words.join(" "),
+                       location(&self.file),
+                       self.line,
+                       self.text)?;
+            } else {
+                write!(f, "\n{:08x}  {}", address, words.join(" "))?;
+            }
+        }
+
+        Ok(())
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.assembly.flush()
+// Returns the start of a diagnostic for a problem in `file`.
+fn location(file: &Option<PathBuf>) -> String {
+    match *file {
+        Some(ref file) => format!("{}:", file.display()),
+        None => String::new(),
     }
 }
 
 #[derive(Debug)]
 pub enum CompilerError {
+    /// The file passed to `compile_file` couldn't be read.
+    Io(PathBuf, io::Error),
     FromUtf8Error(string::FromUtf8Error),
+    /// Every problem found in the program.
+    Diagnostics(Vec<Diagnostic>),
+}
+
+impl fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompilerError::Io(ref path, ref error) => write!(f, "{}: {}", path.display(), error),
+            CompilerError::FromUtf8Error(ref error) => write!(f, "{}", error),
+            CompilerError::Diagnostics(ref diagnostics) => {
+                for (index, diagnostic) in diagnostics.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
+impl error::Error for CompilerError {}
+
 #[cfg(test)]
 mod tests {
-    use super::Compiler;
+    use super::{Compiler, CompilerError, Diagnostic, Severity};
+    use computer::{Computer, State};
+    use linker::Linker;
+    use object::Object;
+    use script::Script;
     use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::process;
     use std::io::Write;
+    use std::path::{self, PathBuf};
+
+    fn texts(compiler: &Compiler) -> Vec<&str> {
+        compiler.instructions.iter().map(|instruction| instruction.text.as_str()).collect()
+    }
+
+    fn diagnostics(assembly: &str) -> Vec<Diagnostic> {
+        let mut compiler = Compiler::new();
+        compiler.write_all(assembly.as_bytes()).unwrap();
+
+        match compiler.compile() {
+            Err(CompilerError::Diagnostics(diagnostics)) => diagnostics,
+            _ => compiler.diagnostics().to_vec(),
+        }
+    }
 
     #[test]
     fn it_returns_an_error_when_compiling_invalid_utf8() {
         let mut compiler = Compiler::new();
-        compiler.write(&[0, 159, 146, 150]).unwrap();
+        compiler.write_all(&[0, 159, 146, 150]).unwrap();
 
         assert!(compiler.compile().is_err());
     }
 
+    #[test]
+    fn it_compiles_the_same_assembly_twice() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"brk end\nend:").unwrap();
+        compiler.compile().unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(compiler.bytecodes, vec![0x0, 0x4, 0x0, 0x0]);
+    }
+
     #[test]
     fn it_splits_lines_by_line_feed() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0 0 0 0\n0 0 0 0").unwrap();
+        compiler.write_all(b"0 0 0 0\n0 0 0 0").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -438,7 +2348,7 @@ mod tests {
     #[test]
     fn it_splits_lines_by_vertical_tab() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0 0 0 0\x0B0 0 0 0").unwrap();
+        compiler.write_all(b"0 0 0 0\x0B0 0 0 0").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -447,7 +2357,7 @@ mod tests {
     #[test]
     fn it_splits_lines_by_form_feed() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0 0 0 0\x0C0 0 0 0").unwrap();
+        compiler.write_all(b"0 0 0 0\x0C0 0 0 0").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -456,7 +2366,7 @@ mod tests {
     #[test]
     fn it_splits_lines_by_carriage_return() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0 0 0 0\r0 0 0 0").unwrap();
+        compiler.write_all(b"0 0 0 0\r0 0 0 0").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -465,7 +2375,7 @@ mod tests {
     #[test]
     fn it_splits_lines_by_carriage_return_line_feed() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0 0 0 0\r\n0 0 0 0").unwrap();
+        compiler.write_all(b"0 0 0 0\r\n0 0 0 0").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -474,7 +2384,7 @@ mod tests {
     #[test]
     fn it_splits_lines_by_next_line() {
         let mut compiler = Compiler::new();
-        write!(compiler, "{}", "0 0 0 0\u{0085}0 0 0 0").unwrap();
+        compiler.write_all("0 0 0 0\u{0085}0 0 0 0".as_bytes()).unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -483,7 +2393,7 @@ mod tests {
     #[test]
     fn it_splits_lines_by_line_separator() {
         let mut compiler = Compiler::new();
-        write!(compiler, "{}", "0 0 0 0\u{2028}0 0 0 0").unwrap();
+        compiler.write_all("0 0 0 0\u{2028}0 0 0 0".as_bytes()).unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -492,7 +2402,7 @@ mod tests {
     #[test]
     fn it_splits_lines_by_paragraph_separator() {
         let mut compiler = Compiler::new();
-        write!(compiler, "{}", "0 0 0 0\u{2029}0 0 0 0").unwrap();
+        compiler.write_all("0 0 0 0\u{2029}0 0 0 0".as_bytes()).unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -501,7 +2411,7 @@ mod tests {
     #[test]
     fn it_ignors_trailing_separators() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0 0 0 0\r\n0 0 0 0\r\n").unwrap();
+        compiler.write_all(b"0 0 0 0\r\n0 0 0 0\r\n").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -510,7 +2420,7 @@ mod tests {
     #[test]
     fn it_strips_single_line_comments() {
         let mut compiler = Compiler::new();
-        compiler.write(b"; single line comment\n0 0 0 0\n").unwrap();
+        compiler.write_all(b"; single line comment\n0 0 0 0\n").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -520,7 +2430,7 @@ mod tests {
     #[test]
     fn it_strips_single_line_comments_starting_with_spaces() {
         let mut compiler = Compiler::new();
-        compiler.write(b" ; single line comment\n0 0 0 0\n").unwrap();
+        compiler.write_all(b" ; single line comment\n0 0 0 0\n").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -530,7 +2440,7 @@ mod tests {
     #[test]
     fn it_strips_single_line_comments_starting_with_tabs() {
         let mut compiler = Compiler::new();
-        compiler.write(b"\t; single line comment\n0 0 0 0\n").unwrap();
+        compiler.write_all(b"\t; single line comment\n0 0 0 0\n").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 2);
@@ -540,57 +2450,57 @@ mod tests {
     #[test]
     fn it_strips_inline_comments() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0 0 0 0; inline comment\n").unwrap();
+        compiler.write_all(b"0 0 0 0; inline comment\n").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 1);
-        assert_eq!(compiler.instructions, vec!["0 0 0 0"]);
+        assert_eq!(texts(&compiler), vec!["0 0 0 0"]);
     }
 
     #[test]
     fn it_strips_inline_comments_starting_with_spaces() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0 0 0 0 ; inline comment\n").unwrap();
+        compiler.write_all(b"0 0 0 0 ; inline comment\n").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 1);
-        assert_eq!(compiler.instructions, vec!["0 0 0 0"]);
+        assert_eq!(texts(&compiler), vec!["0 0 0 0"]);
     }
 
     #[test]
     fn it_strips_inline_comments_starting_with_tabs() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0 0 0 0\t; inline comment\n").unwrap();
+        compiler.write_all(b"0 0 0 0\t; inline comment\n").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 1);
-        assert_eq!(compiler.instructions, vec!["0 0 0 0"]);
+        assert_eq!(texts(&compiler), vec!["0 0 0 0"]);
     }
 
     #[test]
     fn it_trims_spaces_from_instructions() {
         let mut compiler = Compiler::new();
-        compiler.write(b" 0 0 0 0 ").unwrap();
+        compiler.write_all(b" 0 0 0 0 ").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 1);
-        assert_eq!(compiler.instructions, vec!["0 0 0 0"]);
+        assert_eq!(texts(&compiler), vec!["0 0 0 0"]);
     }
 
     #[test]
     fn it_trims_tabs_from_instructions() {
         let mut compiler = Compiler::new();
-        compiler.write(b"\t0 0 0 0\t").unwrap();
+        compiler.write_all(b"\t0 0 0 0\t").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.lines.len(), 1);
-        assert_eq!(compiler.instructions, vec!["0 0 0 0"]);
+        assert_eq!(texts(&compiler), vec!["0 0 0 0"]);
     }
 
     #[test]
     fn it_compiles_addresses_for_labels() {
         let mut compiler = Compiler::new();
-        compiler.write(b"first:\nsecond:\n0 0 0 0\nthird:").unwrap();
+        compiler.write_all(b"first:\nsecond:\n0 0 0 0\nthird:").unwrap();
         compiler.compile().unwrap();
 
         let mut labels = HashMap::new();
@@ -602,25 +2512,65 @@ mod tests {
     }
 
     #[test]
-    fn it_lets_the_last_label_win() {
+    fn it_reports_labels_defined_twice() {
         let mut compiler = Compiler::new();
-        compiler.write(b"label:\n0 0 0 0\nlabel:").unwrap();
-        compiler.compile().unwrap();
+        compiler.write_all(b"label:\n0 0 0 0\nlabel:").unwrap();
+        compiler.compile().unwrap_err();
+
+        assert_eq!(vec![Diagnostic {
+                            severity: Severity::Error,
+                            file: None,
+                            line: 3,
+                            column: 1,
+                            token: "label".to_string(),
+                            message: "label `label` is already defined on line 1".to_string(),
+                            expansions: vec![],
+                        }],
+                   compiler.diagnostics());
+    }
 
-        let mut labels = HashMap::new();
-        labels.insert("label".to_string(), 4);
+    #[test]
+    fn it_reports_every_problem_with_its_line_and_column() {
+        let found: Vec<(usize, usize, String)> = diagnostics("
+        add x 1g 10000000000
+          nop 0 0 0 0 0
+        ; bad: comments are fine
+        \tfoo /z
+        ")
+            .into_iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.column, diagnostic.message))
+            .collect();
+
+        assert_eq!(vec![(2, 13, "undefined label `x`".to_string()),
+                        (2, 15, "invalid number `1g`".to_string()),
+                        (2, 18, "invalid number `10000000000`".to_string()),
+                        (3, 21, "too many operands, expected at most 3".to_string()),
+                        (5, 10, "unknown mnemonic `foo`".to_string()),
+                        (5, 14, "undefined label `z`".to_string())],
+                   found);
+    }
 
-        assert_eq!(compiler.labels, labels);
+    #[test]
+    fn it_formats_diagnostics_like_other_compilers() {
+        let error = {
+            let mut compiler = Compiler::new();
+            compiler.write_all(b"brk\n  lpc nowhere").unwrap();
+            compiler.compile().unwrap_err()
+        };
+
+        assert_eq!("2:7: error: undefined label `nowhere`", error.to_string());
+        assert_eq!("nowhere", diagnostics("brk\n  lpc nowhere")[0].token);
     }
 
     #[test]
     fn it_ignores_trailing_label_characters() {
         let mut compiler = Compiler::new();
-        compiler.write(b"label:with bits\n0 0 0 0\nlabel:with bytes").unwrap();
+        compiler.write_all(b"label:with bits\n0 0 0 0\nother:with bytes").unwrap();
         compiler.compile().unwrap();
 
         let mut labels = HashMap::new();
-        labels.insert("label".to_string(), 4);
+        labels.insert("label".to_string(), 0);
+        labels.insert("other".to_string(), 4);
 
         assert_eq!(compiler.labels, labels);
     }
@@ -628,7 +2578,7 @@ mod tests {
     #[test]
     fn it_parses_hex_for_opcode_0() {
         let mut compiler = Compiler::new();
-        compiler.write(b"0").unwrap();
+        compiler.write_all(b"0").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.bytecodes, vec![0x0, 0x0, 0x0, 0x0]);
@@ -637,7 +2587,7 @@ mod tests {
     #[test]
     fn it_parses_brk_as_opcode_0() {
         let mut compiler = Compiler::new();
-        compiler.write(b"brk").unwrap();
+        compiler.write_all(b"brk").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.bytecodes, vec![0x0, 0x0, 0x0, 0x0]);
@@ -646,7 +2596,7 @@ mod tests {
     #[test]
     fn it_parses_operand_a_as_hex() {
         let mut compiler = Compiler::new();
-        compiler.write(b"brk f").unwrap();
+        compiler.write_all(b"brk f").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.bytecodes, vec![0x0, 0xf, 0x0, 0x0]);
@@ -655,7 +2605,7 @@ mod tests {
     #[test]
     fn it_parses_operand_a_as_a_label() {
         let mut compiler = Compiler::new();
-        compiler.write(b"brk end\nend:").unwrap();
+        compiler.write_all(b"brk end\nend:").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.bytecodes, vec![0x0, 0x4, 0x0, 0x0]);
@@ -664,7 +2614,7 @@ mod tests {
     #[test]
     fn it_parses_operand_b_as_hex() {
         let mut compiler = Compiler::new();
-        compiler.write(b"brk f f").unwrap();
+        compiler.write_all(b"brk f f").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.bytecodes, vec![0x0, 0xf, 0xf, 0x0]);
@@ -673,7 +2623,7 @@ mod tests {
     #[test]
     fn it_parses_operand_b_as_a_label() {
         let mut compiler = Compiler::new();
-        compiler.write(b"brk f end\nend:").unwrap();
+        compiler.write_all(b"brk f end\nend:").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.bytecodes, vec![0x0, 0xf, 0x4, 0x0]);
@@ -682,7 +2632,7 @@ mod tests {
     #[test]
     fn it_parses_operand_c_as_hex() {
         let mut compiler = Compiler::new();
-        compiler.write(b"brk f f f").unwrap();
+        compiler.write_all(b"brk f f f").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.bytecodes, vec![0x0, 0xf, 0xf, 0xf]);
@@ -691,7 +2641,7 @@ mod tests {
     #[test]
     fn it_parses_operand_c_as_a_label() {
         let mut compiler = Compiler::new();
-        compiler.write(b"brk f f end\nend:").unwrap();
+        compiler.write_all(b"brk f f end\nend:").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.bytecodes, vec![0x0, 0xf, 0xf, 0x4]);
@@ -700,18 +2650,752 @@ mod tests {
     #[test]
     fn it_parses_operands_as_relative_addresses() {
         let mut compiler = Compiler::new();
-        compiler.write(b"nop\nadd /0 /5 /6").unwrap();
+        compiler.write_all(b"nop\nadd /0 /5 /6").unwrap();
         compiler.compile().unwrap();
 
         assert_eq!(compiler.bytecodes,
                    vec![0x10, 0x0, 0x0, 0x0, 0x7, 0x4, 0x9, 0xa]);
     }
 
+    fn run(assembly: &str) -> (Computer, HashMap<String, u32>) {
+        let mut compiler = Compiler::new();
+        compiler.write_all(assembly.as_bytes()).unwrap();
+
+        let mut computer = Computer::new();
+        computer.load_from_slice(compiler.compile().unwrap());
+        computer.run_for(100);
+
+        (computer, compiler.labels().clone())
+    }
+
+    #[test]
+    fn it_expands_jumps_and_moves() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        start:
+          jmp start
+          jeq 10 start
+          jne 10 start
+          mov 10 /1
+          not 10 11
+        ")
+            .unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(compiler.bytecodes,
+                   vec![0x1, 0x2, 0x0, 0x0,
+                        0x2, 0x7, 0x10, 0x0,
+                        0x2, 0xb, 0x10, 0x10, 0x1, 0xe, 0x0, 0x0,
+                        0x4, 0x10, 0x11, 0x0,
+                        0xd, 0x10, 0x11, 0x11]);
+    }
+
+    #[test]
+    fn it_runs_conditional_jumps() {
+        let (computer, labels) = run("
+          jne zero fail
+          jeq zero next
+          brk
+        next:
+          jne one pass
+        fail:
+          brk
+        pass:
+          jeq one fail
+          mov result one
+          brk
+        zero:
+          0
+        one:
+          1
+        result:
+          0
+        ");
+
+        assert_eq!(1, computer.peek(labels["result"]));
+    }
+
+    #[test]
+    fn it_runs_bitwise_pseudo_instructions() {
+        let (computer, labels) = run("
+          and x a b
+          or y a b
+          not z a
+          brk
+        a:
+          c
+        b:
+          a
+        x:
+          0
+        y:
+          0
+        z:
+          0
+        ");
+
+        assert_eq!(0x8, computer.peek(labels["x"]));
+        assert_eq!(0xe, computer.peek(labels["y"]));
+        assert_eq!(0xffff_fff3, computer.peek(labels["z"]));
+    }
+
+    #[test]
+    fn it_calls_routines_more_than_once() {
+        let (computer, labels) = run("
+          call increment
+          call increment
+          call increment
+          brk
+        increment:
+          nop
+          add x x one
+          ret increment
+        x:
+          0
+        one:
+          1
+        ");
+
+        assert_eq!(3, computer.peek(labels["x"]));
+        assert_eq!((0x3c, State::Halted), (computer.counter(), computer.state()));
+    }
+
+    #[test]
+    fn it_reports_pseudo_instructions_with_the_wrong_operands() {
+        let messages: Vec<String> = diagnostics("jmp\njeq 1 2 3\ncall nowhere")
+            .into_iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect();
+
+        assert_eq!(vec!["1:1: error: `jmp` expects 1 operand, found 0",
+                        "2:1: error: `jeq` expects 2 operands, found 3",
+                        "3:6: error: undefined label `nowhere`"],
+                   messages);
+    }
+
+    #[test]
+    fn it_substitutes_macro_operands() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        .macro swap a b t
+          mov t a
+          mov a b
+          mov b t
+        .endm
+        swap 10 11 12
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec!["lea 12 10", "lea 10 11", "lea 11 12"], texts(&compiler));
+    }
+
+    #[test]
+    fn it_gives_every_expansion_its_own_local_labels() {
+        let (computer, labels) = run("
+        .macro countdown n
+        %loop:
+          sub n n one
+          jne n %loop
+        .endm
+          countdown x
+          countdown y
+          brk
+        x:
+          3
+        y:
+          5
+        one:
+          1
+        ");
+
+        assert_eq!((0, 0), (computer.peek(labels["x"]), computer.peek(labels["y"])));
+        assert_eq!(Some(&0), labels.get("%loop.1"));
+        assert_eq!(Some(&0xc), labels.get("%loop.2"));
+        assert_eq!(State::Halted, computer.state());
+    }
+
+    #[test]
+    fn it_notes_the_macro_calls_that_led_to_a_problem() {
+        let error = {
+            let mut compiler = Compiler::new();
+            compiler.write_all(b".macro inner
+  lpc nowhere
+.endm
+.macro outer
+  inner
+.endm
+  outer")
+                .unwrap();
+            compiler.compile().unwrap_err()
+        };
+
+        assert_eq!("2:7: error: undefined label `nowhere`\n\
+                    5:3: note: in expansion of macro `inner`\n\
+                    7:3: note: in expansion of macro `outer`",
+                   error.to_string());
+    }
+
+    #[test]
+    fn it_reports_macros_that_are_defined_or_used_wrong() {
+        let messages: Vec<String> = diagnostics("
+.macro forever
+  forever
+.endm
+.macro add x
+.endm
+.macro twice x
+  x
+.endm
+  forever
+  twice
+.endm
+.macro
+.macro open")
+            .into_iter()
+            .map(|diagnostic| diagnostic.to_string().lines().next().unwrap().to_string())
+            .collect();
+
+        assert_eq!(vec!["3:3: error: macro `forever` never stops expanding",
+                        "5:8: error: `add` is already an instruction",
+                        "11:3: error: macro `twice` expects 1 operand, found 0",
+                        "12:1: error: `.endm` without `.macro`",
+                        "13:1: error: `.macro` needs a name",
+                        "14:1: error: `.macro` without `.endm`"],
+                   messages);
+    }
+
+    #[test]
+    fn it_emits_exactly_the_words_directives_ask_for() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        .word 1 here /1
+        here:
+        .fill 3 here
+        .zero 2
+        .align 4
+        .align 4
+        .org a
+        end:
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0x1, 0x3, 0x1,
+                        0x3, 0x3, 0x3,
+                        0x0, 0x0,
+                        0x0, 0x0],
+                   compiler.bytecodes);
+        assert_eq!(Some(&0xa), compiler.labels().get("end"));
+    }
+
+    #[test]
+    fn it_reports_directives_it_cant_size() {
+        let messages: Vec<String> = diagnostics("
+        .word
+        .fill 2
+        .zero x
+        .align 0
+        .zero 5
+        .org 2
+        .zero ffffffff
+        .byte 1
+        .word nowhere")
+            .into_iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect();
+
+        assert_eq!(vec!["2:9: error: `.word` expects at least 1 operand",
+                        "3:9: error: `.fill` expects 2 operands, found 1",
+                        "4:15: error: invalid number `x`",
+                        "5:16: error: can't align to 0 words",
+                        "7:14: error: `.org` can't go back from 5 to 2",
+                        "8:15: error: the program doesn't fit in memory",
+                        "9:9: error: unknown directive `.byte`",
+                        "10:15: error: undefined label `nowhere`"],
+                   messages);
+    }
+
+    #[test]
+    fn it_keeps_quoted_characters_out_of_comments_and_labels() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        .word ';' ':' ' ' '\\'' '\\n' ; a comment
+        .ascii \"a: \\\"b\\\" ;\" ; another
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0x3b, 0x3a, 0x20, 0x27, 0xa,
+                        0x61, 0x3a, 0x20, 0x22, 0x62, 0x22, 0x20, 0x3b],
+                   compiler.bytecodes);
+        assert!(compiler.labels.is_empty());
+    }
+
+    #[test]
+    fn it_packs_four_characters_into_a_word() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b".pack \"abcd\"\n.packz \"abcd\"\n.packz \"ab\"\nend:").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0x64636261, 0x64636261, 0x0, 0x6261], compiler.bytecodes);
+        assert_eq!(Some(&0x4), compiler.labels().get("end"));
+    }
+
+    #[test]
+    fn it_runs_programs_comparing_key_presses_to_characters() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+          key x
+          sub x x letter-a
+          beq /3 x yes
+          brk
+        yes:
+          mov x letter-a
+          brk
+        x:
+          .word 0
+        letter-a:
+          .word 'a'
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        let mut computer = Computer::new().script(Script::parse("step 0 'a'").unwrap());
+        computer.load_from_slice(&compiler.bytecodes);
+        computer.run();
+
+        assert_eq!(0x61, computer.peek(compiler.labels()["x"]));
+    }
+
+    #[test]
+    fn it_reports_characters_and_strings_it_cant_read() {
+        let messages: Vec<String> = diagnostics("
+        .word 'ab' '\\q' 'é'
+        .ascii \"open
+        .asciz 'a'
+        .pack
+        lpc ''")
+            .into_iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect();
+
+        assert_eq!(vec!["2:15: error: `'ab'` isn't a single character",
+                        "2:20: error: unknown escape `\\q`",
+                        "2:25: error: `é` isn't an ASCII character",
+                        "3:16: error: unterminated string",
+                        "4:16: error: unterminated string",
+                        "5:9: error: `.pack` expects 1 operand, found 0",
+                        "6:13: error: `''` isn't a single character"],
+                   messages);
+    }
+
+    #[test]
+    fn it_parses_numbers_with_prefixes() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"0x1f #31 0d31 0o37\n0b11111 0b 0d /0x4").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0x1f, 0x1f, 0x1f, 0x1f, 0x1f, 0xb, 0xd, 0x8], compiler.bytecodes);
+    }
+
+    #[test]
+    fn it_switches_the_default_radix() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        .radix 10
+          add 10 /16 0x10
+          .fill 2 12
+        .radix 2
+          .word 10 /10
+          jmp 11
+        .radix 16
+          .word 10
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0x7, 0xa, 0x10, 0x10,
+                        0xc, 0xc,
+                        0x2, 0x8,
+                        0x1, 0xa, 0x3, 0x0,
+                        0x10],
+                   compiler.bytecodes);
+    }
+
+    #[test]
+    fn it_reports_numbers_outside_the_radix() {
+        let messages: Vec<String> = diagnostics("
+        .radix 10
+        lpc a 1f
+        .radix 2
+        .word 2 -1 +1
+        .radix 3
+        .radix
+        1g 0x")
+            .into_iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect();
+
+        assert_eq!(vec!["3:13: error: undefined label `a`",
+                        "3:15: error: invalid number `1f`",
+                        "5:15: error: invalid number `2`",
+                        "5:20: error: unexpected `+`",
+                        "6:16: error: the radix must be 2, 8, 10 or 16",
+                        "7:9: error: `.radix` expects 1 operand, found 0",
+                        "8:9: error: invalid number `1g`",
+                        "8:12: error: invalid number `0x`"],
+                   messages);
+    }
+
+    #[test]
+    fn it_evaluates_expressions_in_operands() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        font-a:
+          .word font-a+10 2+3*4 (2+3)*4 7/2 -1 'a'+1 '-'-1
+          lpc /(2*2) font-a-1 two-two
+        two-two:
+        two:
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0x10, 0xe, 0x14, 0x3, 0xffff_ffff, 0x62, 0x2c,
+                        0x1, 0xb, 0xffff_ffff, 0xb],
+                   compiler.bytecodes);
+    }
+
+    #[test]
+    fn it_evaluates_constants_in_any_order() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        .equ area width*height
+        .equ width 10
+        .radix 10
+        .equ height 10
+          .word area last
+        .equ last /1
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0xa0, 0x3], compiler.bytecodes);
+        assert_eq!(Some(&0xa0), compiler.constants().get("area"));
+        assert!(compiler.labels().is_empty());
+    }
+
+    #[test]
+    fn it_reports_expressions_it_cant_evaluate() {
+        let messages: Vec<String> = diagnostics("
+        .word (1+2 1/0 x-y 1+ 1)
+        .equ a b+1
+        .equ b a
+        .equ c 1
+        .equ c 2
+        .equ e
+        .equ d nowhere
+        d:")
+            .into_iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect();
+
+        assert_eq!(vec!["2:15: error: expected `)`",
+                        "2:20: error: division by zero",
+                        "2:24: error: undefined label `x-y`",
+                        "2:28: error: expected a value",
+                        "2:31: error: unexpected `)`",
+                        "3:14: error: constant `a` is defined in terms of itself",
+                        "6:14: error: constant `c` is already defined on line 5",
+                        "7:9: error: `.equ` expects 2 operands, found 1",
+                        "8:14: error: `d` is already a label"],
+                   messages);
+    }
+
+    #[test]
+    fn it_scopes_local_labels_to_the_last_global_label() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        .top:
+          .word .top
+        first:
+        .loop:
+          .word .loop .loop+1
+        %macro:
+          .word .loop
+        second:
+          .word .loop first.loop
+        .loop:
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0x0, 0x1, 0x2, 0x1, 0x6, 0x1], compiler.bytecodes);
+        assert_eq!(Some(&0x1), compiler.labels().get("first.loop"));
+        assert_eq!(Some(&0x6), compiler.labels().get("second.loop"));
+    }
+
+    #[test]
+    fn it_finds_the_closest_numeric_label_in_either_direction() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        1:
+          .word @1b @1f @2f
+        1:
+        2:
+          .word @1b @2b+1 @1f
+        1:
+          .word @1b 3f
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0x0, 0x3, 0x3, 0x3, 0x4, 0x6, 0x6, 0x3f], compiler.bytecodes);
+        assert!(compiler.labels().is_empty());
+    }
+
+    #[test]
+    fn it_keeps_hex_numbers_apart_from_numeric_labels() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        1:
+          .word 1f 1b 2b @1b
+        2:
+          .word 2b 1f+1 @2b
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!(vec![0x1f, 0x1b, 0x2b, 0x0, 0x2b, 0x20, 0x4], compiler.bytecodes);
+    }
+
+    #[test]
+    fn it_reports_numeric_labels_that_arent_there() {
+        let messages: Vec<String> = diagnostics("
+          jmp @1b
+        1:
+          jmp @1f
+          jmp .nowhere
+          jmp @x")
+            .into_iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect();
+
+        assert_eq!(vec!["2:15: error: no `1:` label before this line",
+                        "4:15: error: no `1:` label after this line",
+                        "5:15: error: undefined label `.nowhere`",
+                        "6:15: error: undefined label `@x`"],
+                   messages);
+    }
+
+    #[test]
+    fn it_relocates_every_word_holding_an_address() {
+        let mut compiler = Compiler::new().relocatable();
+        compiler.write_all(b"
+        .equ length end-start
+        .equ middle start+2
+        start:
+          jmp .skip
+        .skip:
+        1:
+          .word middle length @1b*1 'a'
+        end:
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        let object = compiler.object().unwrap().clone();
+        let relocated: Vec<u32> = object.relocations.iter().map(|relocation| relocation.address).collect();
+        assert_eq!(vec![0x1, 0x2, 0x4, 0x6], relocated);
+
+        let padding = Object {
+            code: vec![0x0; 3],
+            ..Object::default()
+        };
+        let program = Linker::new().object("padding", padding).object("main", object).link().unwrap();
+        assert_eq!(vec![0x1, 0x5, 0x7, 0x0, 0x5, 0x8, 0x7, 0x61], program.code[3..].to_vec());
+    }
+
+    #[test]
+    fn it_keeps_aligned_words_aligned_in_linked_objects() {
+        let mut compiler = Compiler::new().relocatable();
+        compiler.write_all(b"
+        .global table
+          .word 1
+        .align 4
+        table:
+          .word 2
+        .align 6
+          .word table
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        let object = compiler.object().unwrap().clone();
+        assert_eq!(0xc, object.align);
+
+        let padding = Object {
+            code: vec![0x0; 3],
+            ..Object::default()
+        };
+        let program = Linker::new().object("padding", padding).object("main", object).link().unwrap();
+        assert_eq!(Some(&0x10), program.exports.get("table"));
+        assert_eq!(vec![0x1, 0x0, 0x0, 0x0, 0x2, 0x0, 0x10], program.code[0xc..].to_vec());
+    }
+
+    #[test]
+    fn it_reports_symbols_it_cant_export_import_or_relocate() {
+        let messages = |compiler: &mut Compiler, assembly: &str| -> Vec<String> {
+            compiler.write_all(assembly.as_bytes()).unwrap();
+            match compiler.compile() {
+                Err(CompilerError::Diagnostics(diagnostics)) => {
+                    diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect()
+                }
+                _ => panic!("expected diagnostics"),
+            }
+        };
+
+        assert_eq!(vec!["2:9: error: `.extern` only works in relocatable objects"],
+                   messages(&mut Compiler::new(), "
+        .extern print
+          .word print"));
+
+        assert_eq!(vec!["2:23: error: undefined label `nowhere`",
+                        "2:31: error: constant `size` can't be exported, only labels can",
+                        "3:17: error: `start` is defined here, so it can't be external"],
+                   messages(&mut Compiler::new().relocatable(), "
+        .global start nowhere size
+        .extern start print
+        .equ size 4
+        start:
+          .word print"));
+
+        assert_eq!(vec!["4:17: error: `start*2` isn't an address plus or minus a number, so it \
+                         can't be relocated",
+                        "4:25: error: `print-start` isn't an address plus or minus a number, so \
+                         it can't be relocated"],
+                   messages(&mut Compiler::new().relocatable(), "
+        .extern print
+        start:
+          .word start*2 print-start end-start
+        end:"));
+
+        assert_eq!(vec!["3:16: error: can't align the object to 10001 words as well as 10000"],
+                   messages(&mut Compiler::new().relocatable(), "
+        .align 10000
+        .align 10001"));
+    }
+
+    #[test]
+    fn it_lists_every_line_with_its_address_and_words() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        .macro twice x
+          .word x x
+        .endm
+        .equ size 2
+        start:
+          not x x
+          twice 1
+          twice 2
+        x:
+          .asciz \"Hi!\"  ; Four words
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        let listing = compiler.listing();
+        let lines: Vec<(u32, usize, usize)> = listing.iter()
+            .map(|line| (line.address, line.line, line.words.len()))
+            .collect();
+        assert_eq!(vec![(0x0, 5, 0), (0x0, 6, 0), (0x0, 7, 4), (0x4, 3, 2), (0x6, 3, 2), (0x8, 10, 0), (0x8, 11, 4)],
+                   lines);
+        assert_eq!(compiler.bytecodes,
+                   listing.iter().flat_map(|line| line.words.clone()).collect::<Vec<u32>>());
+
+        assert_eq!("00000008  00000048 00000069 00000021 00000000  11            .asciz \"Hi!\"  ; Four \
+                    words",
+                   listing[6].to_string());
+    }
+
+    #[test]
+    fn it_lists_lines_with_more_than_four_words_over_several_rows() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"  .fill 6 ff").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!("00000000  000000ff 000000ff 000000ff 000000ff  1    .fill 6 ff\n\
+                    00000004  000000ff 000000ff",
+                   compiler.listing()[0].to_string());
+    }
+
+    #[test]
+    fn it_maps_labels_in_order_of_address() {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        b:
+          nop
+        a:
+        c:
+          brk
+        ").unwrap();
+        compiler.compile().unwrap();
+
+        assert_eq!("00000000 b\n00000004 a\n00000004 c\n", compiler.symbol_map());
+    }
+
+    // Writes `files` to a new directory named after `test` and returns it.
+    fn files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = env::temp_dir().join(format!("chifir-compiler-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        for &(name, assembly) in files {
+            let path = directory.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, assembly).unwrap();
+        }
+
+        directory
+    }
+
+    #[test]
+    fn it_includes_files_next_to_the_file_then_from_include_directories() {
+        let directory = files("include",
+                              &[("main.asm", ".include \"src/a.asm\"\n.include \"c.asm\"\n.include \"d.asm\""),
+                                ("src/a.asm", "a:\n  .word 1\n.include \"b.asm\""),
+                                ("src/b.asm", "b:\n  .word 2"),
+                                ("c.asm", ".word 3"),
+                                ("lib/c.asm", ".word 4"),
+                                ("lib/d.asm", ".word a b")]);
+
+        let mut compiler = Compiler::new().include_dir(directory.join("lib"));
+        compiler.compile_file(directory.join("main.asm")).unwrap();
+
+        assert_eq!(vec![0x1, 0x2, 0x3, 0x0, 0x1], compiler.bytecodes);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn it_reports_the_file_of_every_problem() {
+        let directory = files("include-problems",
+                              &[("main.asm", ".include \"a.asm\"\n.include \"nowhere.asm\"\n.include"),
+                                ("a.asm", "  lpc nowhere\n.include \"b.asm\""),
+                                ("b.asm", ".include \"a.asm\"")]);
+
+        let mut compiler = Compiler::new();
+        let error = compiler.compile_file(directory.join("main.asm")).unwrap_err().to_string();
+        let prefix = format!("{}{}", directory.display(), path::MAIN_SEPARATOR);
+
+        assert_eq!(vec!["a.asm:1:7: error: undefined label `nowhere`",
+                        "b.asm:1:10: error: `a.asm` includes itself",
+                        "main.asm:2:10: error: can't find `nowhere.asm`",
+                        "main.asm:3:1: error: `.include` expects 1 operand, found 0"],
+                   error.lines().map(|line| line.replace(&prefix, "")).collect::<Vec<String>>());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn it_reports_files_it_cant_read() {
+        let path = env::temp_dir().join(format!("chifir-compiler-missing-{}.asm", process::id()));
+
+        match Compiler::new().compile_file(&path) {
+            Err(CompilerError::Io(missing, _)) => assert_eq!(path, missing),
+            _ => panic!("expected an I/O error"),
+        }
+    }
+
     #[test]
     fn it_compiles_the_simple_ctrl_c_example() {
         let mut compiler = Compiler::new();
 
-        compiler.write(b"
+        compiler.write_all(b"
         f 2 0 3  ; Read key press and store it in M[2]
         8 2 2 3  ; Subtract M[3] from M[2] and store the result in M[2]
         2 b 2 f  ; If M[2] equals 0, then set PC to M[b]
@@ -733,7 +3417,7 @@ mod tests {
     fn it_compiles_the_complex_ctrl_c_example() {
         let mut compiler = Compiler::new();
 
-        compiler.write(b"
+        compiler.write_all(b"
         check-ctrl-c:
           key x                ; Read key press and store it in M[x]
           sub x x ctrl-c       ; Subtract M[ctrl-c] from M[x] and store the result in M[x]
@@ -1,22 +1,131 @@
 //! A virtual computer for executing bytecode.
 
-use termion;
-
-use super::sixel;
-use std::io::{self, Read, Write, Cursor};
+use super::display::{self, Display, Frame, Mode, Sixel};
+use super::history::{Checkpoint, History, Undo};
+use super::memory::Memory;
+use super::script::Script;
+use super::snapshot::Snapshot;
+use super::trace::{Record, Store, TraceWriter};
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
 use std::marker::Send;
+use std::ops::Range;
 use std::vec::Vec;
 
+/// The execution state of a `Computer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// The last instruction finished and the next one is ready to run.
+    Running,
+    /// The last instruction was `brk`.
+    Halted,
+    /// The last instruction had an opcode the computer doesn't understand.
+    IllegalOpcode(u32),
+    /// The last instruction was `key`, but no key had been pressed.
+    WaitingForInput,
+    /// The last instruction couldn't be written to the trace. The error is
+    /// available from `Computer::trace_error`.
+    TraceFailed,
+    /// The last instruction was `drw`, but the display couldn't show the
+    /// frame. The error is available from `Computer::display_error`.
+    DisplayFailed,
+}
+
+/// The reason a call to `run` or `run_for` returned.
+///
+/// Every reason carries the program counter at the time execution stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// A `brk` instruction was executed.
+    Halted { pc: u32 },
+    /// An instruction with an unknown opcode was executed.
+    IllegalOpcode { pc: u32, opcode: u32 },
+    /// A `key` instruction was executed, but no key had been pressed.
+    WaitingForInput { pc: u32 },
+    /// The maximum number of steps passed to `run_for` were executed.
+    StepLimit { pc: u32 },
+    /// The next instruction is at an address with a breakpoint.
+    Breakpoint { pc: u32 },
+    /// The instruction at `pc` accessed memory covered by a watchpoint.
+    ///
+    /// For reads, `old` and `new` are both the value that was read.
+    Watchpoint {
+        pc: u32,
+        address: u32,
+        access: Access,
+        old: u32,
+        new: u32,
+    },
+    /// `reverse_continue` went back as far as the recorded history goes.
+    StartOfHistory { pc: u32 },
+    /// The instruction before `pc` couldn't be written to the trace.
+    TraceFailed { pc: u32 },
+    /// The display couldn't show the frame drawn by the instruction before
+    /// `pc`.
+    DisplayFailed { pc: u32 },
+}
+
+/// The kinds of memory access a watchpoint can trigger on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn covers(&self, access: Access) -> bool {
+        *self == Access::ReadWrite || *self == access
+    }
+}
+
+/// A range of memory addresses that stops execution when accessed.
+///
+/// Only the memory an instruction operates on is watched. Fetching the
+/// instruction itself and refreshing the screen don't trigger watchpoints.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<u32>,
+    pub access: Access,
+}
+
+struct WatchpointHit {
+    address: u32,
+    access: Access,
+    old: u32,
+    new: u32,
+}
+
 pub struct Computer {
-    memory: Vec<u32>,
+    memory: Memory,
     counter: u32,
-    input: Option<Box<Read + Send>>,
-    output: Option<Box<Write + Send>>,
+    state: State,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    watchpoint_hit: Option<WatchpointHit>,
+    input: Option<Box<dyn Read + Send>>,
+    display: Option<Box<dyn Display + Send>>,
+    display_error: Option<io::Error>,
     keyboard: Option<u8>,
+    script: Option<Script>,
+    steps: u64,
+    frames: u64,
+    trace: Option<TraceWriter<Box<dyn Write + Send>>>,
+    trace_error: Option<io::Error>,
+    record: Option<Record>,
+    history: Option<History>,
+    undo: Option<Undo>,
+    replaying: bool,
     display_address: u32,
     display_width: u32,
     display_height: u32,
-    display: Vec<u8>,
+    display_mode: Mode,
+    // Bands of the display written since the last frame, unless the whole
+    // display has to be redrawn anyway.
+    dirty: BTreeSet<u32>,
+    redraw: bool,
+    frame: Option<Frame>,
+    encoded: Option<Vec<u8>>,
     read_position: usize,
 }
 
@@ -26,6 +135,11 @@ impl Computer {
     /// The computer will start without any memory. The program counter will
     /// start at zero.
     ///
+    /// Memory is sparse. Above the first 2<sup>20</sup> words, pages of 4096
+    /// words are allocated the first time they're written to, and reading
+    /// memory that was never written returns zero. Programs can use the full
+    /// 32 bit address space.
+    ///
     /// # Example
     ///
     /// ```
@@ -38,15 +152,33 @@ impl Computer {
     /// ```
     pub fn new() -> Self {
         Computer {
-            memory: Vec::new(),
+            memory: Memory::new(),
             counter: 0,
+            state: State::Running,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
             input: None,
-            output: None,
+            display: None,
+            display_error: None,
             keyboard: None,
+            script: None,
+            steps: 0,
+            frames: 0,
+            trace: None,
+            trace_error: None,
+            record: None,
+            history: None,
+            undo: None,
+            replaying: false,
             display_address: 1_048_576,
             display_width: 512,
             display_height: 684,
-            display: Vec::new(),
+            display_mode: Mode::Monochrome,
+            dirty: BTreeSet::new(),
+            redraw: true,
+            frame: None,
+            encoded: None,
             read_position: 0,
         }
     }
@@ -94,14 +226,46 @@ impl Computer {
     ///
     /// assert_eq!([0xf, 0x2, 0x71, 0x0], computer.dump());
     /// ```
-    pub fn input(mut self, input: Box<Read + Send>) -> Self {
+    pub fn input(mut self, input: Box<dyn Read + Send>) -> Self {
         self.input = Some(input);
         self
     }
 
+    /// Binds a script that presses keys at fixed points in execution.
+    ///
+    /// Scripted keys are read in place of keys from the input reader, so
+    /// runs are the same every time. The `script` module describes the
+    /// format.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::{Computer, StopReason};
+    /// use chifir::script::Script;
+    ///
+    /// let script = Script::parse("frame 1 'q'").unwrap();
+    ///
+    /// let mut computer = Computer::new().script(script);
+    /// computer.load(vec![
+    ///     0xe, 0x0, 0x0, 0x0,  // drw
+    ///     0xf, 0xc, 0x0, 0x0,  // key c
+    ///     0x0, 0x0, 0x0, 0x0,  // brk
+    /// ]);
+    ///
+    /// assert_eq!(1, computer.pending_keys());
+    /// assert_eq!(computer.run(), StopReason::Halted { pc: 0x8 });
+    /// assert_eq!(0x71, computer.peek(0xc));
+    /// assert_eq!(0, computer.pending_keys());
+    /// ```
+    pub fn script(mut self, script: Script) -> Self {
+        self.script = Some(script);
+        self
+    }
+
     /// Binds a writer for getting display output.
     ///
-    /// An in memory display is provided through the `Read` trait.
+    /// Frames are drawn as Sixel graphics, see `display::Sixel`. An in
+    /// memory display is provided through the `Read` trait.
     ///
     /// # Examples
     ///
@@ -152,8 +316,121 @@ impl Computer {
     ///
     /// assert_eq!(move_cursor, output[0..6]);
     /// ```
-    pub fn output(mut self, output: Box<Write + Send>) -> Self {
-        self.output = Some(output);
+    pub fn output(self, output: Box<dyn Write + Send>) -> Self {
+        self.display(Box::new(Sixel::new(output)))
+    }
+
+    /// Binds a display that is given every frame drawn with `drw`.
+    ///
+    /// This replaces the writer bound with `output`. The `display` module
+    /// has an example.
+    ///
+    /// The first frame, and the first after the display is reconfigured or
+    /// the computer is loaded, restored or stepped back, is drawn whole.
+    /// Other frames only update the bands that were written since.
+    pub fn display(mut self, display: Box<dyn Display + Send>) -> Self {
+        self.display = Some(display);
+        self.display_error = None;
+        self.redraw = true;
+        self
+    }
+
+    /// Returns the error that stopped the display, if it failed to show a
+    /// frame.
+    pub fn display_error(&self) -> Option<&io::Error> {
+        self.display_error.as_ref()
+    }
+
+    /// Returns the last frame drawn with `drw`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new();
+    /// computer.load(vec![
+    ///     0x11, 0x8, 0x2, 0x1,  // cfv 8 2 1
+    ///     0xe, 0x0, 0x0, 0x0,   // drw
+    ///     0x0, 0x1,
+    /// ]);
+    ///
+    /// assert!(computer.frame().is_none());
+    ///
+    /// computer.run_for(2);
+    ///
+    /// assert_eq!(vec![0x0, 0x1], computer.frame().unwrap().pixels);
+    /// ```
+    pub fn frame(&self) -> Option<&Frame> {
+        self.frame.as_ref()
+    }
+
+    /// Binds a writer for recording a trace of every executed instruction.
+    ///
+    /// The `trace` module describes the format and provides a reader. Wrap
+    /// files in a `BufWriter`, since every instruction makes a small write.
+    ///
+    /// Returns an error if the trace header can't be written. If writing a
+    /// record fails later on, the computer stops with `State::TraceFailed`
+    /// and stops tracing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// let trace = Box::new(Cursor::new(Vec::new()));
+    ///
+    /// let mut computer = Computer::new().trace(trace).unwrap();
+    /// computer.load(vec![
+    ///     0x10, 0x0, 0x0, 0x0,  // nop
+    /// ]);
+    ///
+    /// computer.step();
+    /// ```
+    pub fn trace(mut self, trace: Box<dyn Write + Send>) -> io::Result<Self> {
+        self.trace = Some(TraceWriter::new(trace)?);
+        self.trace_error = None;
+        Ok(self)
+    }
+
+    /// Returns the error that stopped the trace, if writing it failed.
+    pub fn trace_error(&self) -> Option<&io::Error> {
+        self.trace_error.as_ref()
+    }
+
+    /// Records history so that execution can be stepped backwards.
+    ///
+    /// A full checkpoint of memory is taken every `interval` steps, and the
+    /// most recent `checkpoints` of them are kept. Between checkpoints, each
+    /// step only records what it changed. Stepping back past the most recent
+    /// checkpoint replays forward from the one before it, so a larger
+    /// `interval` uses less memory but makes those steps slower.
+    ///
+    /// Changes made with `poke` aren't part of the history.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new().history(1000, 10);
+    /// computer.load(vec![
+    ///     0x4, 0x8, 0x9, 0x0,  // lea 8 9
+    ///     0x0, 0x0, 0x0, 0x0,  // brk
+    ///     0x1, 0x2,
+    /// ]);
+    ///
+    /// computer.step();
+    /// assert_eq!(0x2, computer.peek(0x8));
+    ///
+    /// assert!(computer.step_back());
+    /// assert_eq!(0x1, computer.peek(0x8));
+    /// assert_eq!(0x0, computer.counter());
+    /// ```
+    pub fn history(mut self, interval: u64, checkpoints: usize) -> Self {
+        self.history = Some(History::new(interval, checkpoints));
         self
     }
 
@@ -173,7 +450,175 @@ impl Computer {
     /// assert_eq!(computer.next(), 0x1);
     /// ```
     pub fn next(&self) -> u32 {
-        *self.memory.get(self.counter as usize).unwrap_or(&0)
+        self.memory.peek(self.counter)
+    }
+
+    /// Returns the program counter.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new();
+    ///
+    /// computer.load(vec![
+    ///     0x10, 0x0, 0x0, 0x0,  // nop
+    /// ]);
+    ///
+    /// computer.step();
+    ///
+    /// assert_eq!(computer.counter(), 0x4);
+    /// ```
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    /// Returns the number of instructions executed since the program was
+    /// loaded.
+    ///
+    /// An instruction that waits for input still counts as executed.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Returns the number of frames drawn since the program was loaded.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Returns the number of scripted keys that haven't been pressed yet.
+    pub fn pending_keys(&self) -> usize {
+        self.script.as_ref().map_or(0, |script| script.pending())
+    }
+
+    /// Returns the state left behind by the last executed instruction.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::{Computer, State};
+    ///
+    /// let mut computer = Computer::new();
+    ///
+    /// computer.load(vec![
+    ///     0x12, 0x0, 0x0, 0x0,  // Unknown opcode
+    /// ]);
+    ///
+    /// assert_eq!(computer.state(), State::Running);
+    ///
+    /// computer.step();
+    ///
+    /// assert_eq!(computer.state(), State::IllegalOpcode(0x12));
+    /// ```
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Stops `run` before executing the instruction at `address`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::{Computer, StopReason};
+    ///
+    /// let mut computer = Computer::new();
+    ///
+    /// computer.load(vec![
+    ///     0x10, 0x0, 0x0, 0x0,  // nop
+    ///     0x10, 0x0, 0x0, 0x0,  // nop
+    ///     0x0, 0x0, 0x0, 0x0,   // brk
+    /// ]);
+    ///
+    /// computer.set_breakpoint(0x4);
+    ///
+    /// assert_eq!(computer.run(), StopReason::Breakpoint { pc: 0x4 });
+    /// assert_eq!(computer.run(), StopReason::Halted { pc: 0x8 });
+    /// ```
+    pub fn set_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Removes the breakpoint at `address`.
+    ///
+    /// Returns `true` if there was a breakpoint to remove.
+    pub fn clear_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Returns the addresses that have breakpoints, in ascending order.
+    pub fn breakpoints(&self) -> Vec<u32> {
+        self.breakpoints.iter().cloned().collect()
+    }
+
+    /// Stops `run` after an instruction accesses memory in `range`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::{Access, Computer, StopReason};
+    ///
+    /// let mut computer = Computer::new();
+    ///
+    /// computer.load(vec![
+    ///     0x4, 0x8, 0x9, 0x0,  // lea 8 9
+    ///     0x0, 0x0, 0x0, 0x0,  // brk
+    ///     0x1, 0x2,
+    /// ]);
+    ///
+    /// computer.set_watchpoint(8..9, Access::Write);
+    ///
+    /// assert_eq!(computer.run(), StopReason::Watchpoint {
+    ///     pc: 0x0,
+    ///     address: 0x8,
+    ///     access: Access::Write,
+    ///     old: 0x1,
+    ///     new: 0x2,
+    /// });
+    /// ```
+    pub fn set_watchpoint(&mut self, range: Range<u32>, access: Access) {
+        let watchpoint = Watchpoint { range, access };
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Removes every watchpoint that covers exactly `range`.
+    ///
+    /// Returns `true` if there was a watchpoint to remove.
+    pub fn clear_watchpoint(&mut self, range: Range<u32>) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| watchpoint.range != range);
+        count != self.watchpoints.len()
+    }
+
+    /// Returns the watchpoints in the order they were set.
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.watchpoints.as_slice()
+    }
+
+    /// Returns the range of memory the display is configured to show.
+    ///
+    /// This is useful for watching writes to the screen.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new();
+    ///
+    /// computer.load(vec![
+    ///     0x11, 0x100, 0x10, 0x8,  // cfv 100 10 8
+    /// ]);
+    ///
+    /// computer.step();
+    ///
+    /// assert_eq!(computer.display_region(), 0x100..0x180);
+    /// ```
+    pub fn display_region(&self) -> Range<u32> {
+        let size = self.display_width.saturating_mul(self.display_height);
+        self.display_address..self.display_address.saturating_add(size)
     }
 
     /// Copies the elements from `iter` into memory.
@@ -197,8 +642,21 @@ impl Computer {
     /// ```
     pub fn load<I: IntoIterator<Item = u32>>(&mut self, iter: I) {
         self.memory.clear();
-        self.memory.extend(iter);
+        for (index, value) in iter.into_iter().enumerate() {
+            self.memory.write(index as u32, value);
+        }
         self.counter = 0;
+        self.state = State::Running;
+        self.steps = 0;
+        self.frames = 0;
+        self.redraw = true;
+
+        if let Some(ref mut script) = self.script {
+            script.reset();
+        }
+        if let Some(ref mut history) = self.history {
+            *history = history.reset();
+        }
     }
 
     /// Copies the elements from `slice` into memory.
@@ -221,120 +679,637 @@ impl Computer {
     /// assert_eq!(2, computer.next());
     /// ```
     pub fn load_from_slice(&mut self, slice: &[u32]) {
-        self.memory.clear();
-        self.memory.extend_from_slice(slice);
-        self.counter = 0;
+        self.load(slice.iter().cloned());
+    }
+
+    /// Extracts a slice containing the contents of memory.
+    ///
+    /// The slice runs from address zero up to the highest address that has
+    /// been read or written, but stops at the first 2<sup>20</sup> words.
+    /// This is meant for inspecting small programs. Memory above that can
+    /// still be read with `peek`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new();
+    ///
+    /// computer.load_from_slice(&[
+    ///     1, 2, 3, 4
+    /// ]);
+    ///
+    /// assert_eq!([1, 2, 3, 4], computer.dump());
+    /// ```
+    pub fn dump(&self) -> &[u32] {
+        self.memory.as_slice()
+    }
+
+    /// Returns the value in memory at `address`.
+    ///
+    /// Reading memory this way doesn't trigger watchpoints.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new();
+    ///
+    /// computer.load_from_slice(&[
+    ///     1, 2, 3, 4
+    /// ]);
+    ///
+    /// assert_eq!(3, computer.peek(2));
+    /// assert_eq!(0, computer.peek(0xFFFF_FFFF));
+    /// ```
+    pub fn peek(&self, address: u32) -> u32 {
+        self.memory.peek(address)
+    }
+
+    /// Stores `value` in memory at `address`.
+    ///
+    /// Writing memory this way doesn't trigger watchpoints.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new();
+    ///
+    /// computer.poke(2, 7);
+    ///
+    /// assert_eq!([0, 0, 7], computer.dump());
+    /// ```
+    pub fn poke(&mut self, address: u32, value: u32) {
+        self.mark_dirty(address);
+        self.memory.write(address, value);
+    }
+
+    /// Captures the complete state of the computer.
+    ///
+    /// The `snapshot` module describes what's included and how snapshots
+    /// are saved to files.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new();
+    /// computer.load(vec![
+    ///     0x10, 0x0, 0x0, 0x0,  // nop
+    ///     0x0, 0x0, 0x0, 0x0,   // brk
+    /// ]);
+    ///
+    /// let snapshot = computer.snapshot();
+    /// computer.run();
+    ///
+    /// computer.restore(&snapshot);
+    ///
+    /// assert_eq!(0x0, computer.counter());
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            counter: self.counter,
+            state: self.state,
+            keyboard: self.keyboard,
+            display_address: self.display_address,
+            display_width: self.display_width,
+            display_height: self.display_height,
+            display_mode: self.display_mode,
+            frame: self.frame.clone(),
+            memory: self.memory.clone(),
+        }
+    }
+
+    /// Puts the computer back into the state captured by `snapshot`.
+    ///
+    /// The snapshot's frame is drawn on the bound display, so the screen
+    /// shows what it did when the snapshot was taken. If the display can't
+    /// show it, it's unbound like when `drw` fails. Recorded history is
+    /// cleared, while breakpoints and watchpoints are kept.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.memory = snapshot.memory.clone();
+        self.counter = snapshot.counter;
+        self.state = snapshot.state;
+        self.keyboard = snapshot.keyboard;
+        self.display_address = snapshot.display_address;
+        self.display_width = snapshot.display_width;
+        self.display_height = snapshot.display_height;
+        self.display_mode = snapshot.display_mode;
+        self.frame = snapshot.frame.clone();
+        self.encoded = None;
+        self.read_position = 0;
+        self.watchpoint_hit = None;
+        self.redraw = true;
+
+        if let Some(ref mut history) = self.history {
+            *history = history.reset();
+        }
+
+        let shown = match (self.display.as_mut(), self.frame.as_ref()) {
+            (Some(display), Some(frame)) => display.draw(frame),
+            _ => Ok(()),
+        };
+        if let Err(error) = shown {
+            self.display = None;
+            self.display_error = Some(error);
+        }
+    }
+
+    /// Executes the next instruction.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new();
+    ///
+    /// computer.load(vec![
+    ///     0x1, 0x3, 0x0, 0x8,  // lpc /3 0 8
+    ///     0x0, 0x0, 0x0, 0x0,  // brk
+    ///     0x2, 0xa, 0x4, 0x0,  // beq /2 4
+    /// ]);
+    ///
+    /// assert_eq!(computer.next(), 0x1);
+    ///
+    /// computer.step();
+    /// computer.step();
+    ///
+    /// assert_eq!(computer.next(), 0x0);
+    /// ```
+    pub fn step(&mut self) {
+        if self.history.is_some() {
+            self.begin_undo();
+        }
+
+        let counter = self.counter;
+        let opcode = self.memory.read(counter);
+        let a = self.memory.read(counter.wrapping_add(1));
+        let b = self.memory.read(counter.wrapping_add(2));
+        let c = self.memory.read(counter.wrapping_add(3));
+        self.watchpoint_hit = None;
+
+        if self.trace.is_some() && !self.replaying {
+            self.record = Some(Record {
+                pc: counter,
+                opcode,
+                a,
+                b,
+                c,
+                store: None,
+                key: None,
+            });
+        }
+
+        self.exec(opcode, a, b, c);
+
+        if let Some(record) = self.record.take() {
+            let written = match self.trace {
+                // Flushing once the program ends lets buffered writers report
+                // their errors too.
+                Some(ref mut trace) => {
+                    let ended = matches!(self.state, State::Halted | State::IllegalOpcode(_));
+                    trace.record(&record).and_then(|_| if ended { trace.flush() } else { Ok(()) })
+                }
+                None => Ok(()),
+            };
+            if let Err(error) = written {
+                self.trace = None;
+                self.trace_error = Some(error);
+                self.state = State::TraceFailed;
+            }
+        }
+
+        self.steps += 1;
+
+        if let Some(undo) = self.undo.take() {
+            if let Some(ref mut history) = self.history {
+                history.undo.push(undo);
+                history.step += 1;
+            }
+        }
+    }
+
+    /// Reverses the last executed instruction.
+    ///
+    /// Memory, the program counter, the state and the display configuration
+    /// go back to exactly what they were before the instruction ran. Keys
+    /// read by `key` instructions are remembered, so stepping forward again
+    /// reads the same keys.
+    ///
+    /// Returns `false` if history isn't being recorded or doesn't go back
+    /// any further.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::computer::Computer;
+    ///
+    /// let mut computer = Computer::new().history(2, 10);
+    /// computer.load(vec![
+    ///     0x10, 0x0, 0x0, 0x0,  // nop
+    ///     0x1, 0x6, 0x0, 0x0,   // lpc /2
+    ///     0x0,
+    /// ]);
+    ///
+    /// computer.run_for(4);
+    /// assert_eq!(0x0, computer.counter());
+    ///
+    /// assert!(computer.step_back());
+    /// assert_eq!(0x4, computer.counter());
+    ///
+    /// for _ in 0..3 {
+    ///     assert!(computer.step_back());
+    /// }
+    /// assert!(!computer.step_back());
+    /// ```
+    pub fn step_back(&mut self) -> bool {
+        self.undo_step().is_some()
+    }
+
+    /// Steps backwards until reaching a breakpoint or an instruction that
+    /// wrote to memory covered by a write watchpoint.
+    ///
+    /// Stops at the instruction itself, before it executes, so `step` runs
+    /// it again. Read watchpoints are ignored, since reads aren't recorded.
+    ///
+    /// # Examples
+    ///
+    /// Finding the instruction that last changed a location.
+    ///
+    /// ```
+    /// use chifir::computer::{Access, Computer, StopReason};
+    ///
+    /// let mut computer = Computer::new().history(1000, 10);
+    /// computer.load(vec![
+    ///     0x4, 0xc, 0xd, 0x0,  // lea c d
+    ///     0x10, 0x0, 0x0, 0x0, // nop
+    ///     0x0, 0x0, 0x0, 0x0,  // brk
+    ///     0x0, 0x7,
+    /// ]);
+    ///
+    /// computer.run();
+    /// computer.set_watchpoint(0xc..0xd, Access::Write);
+    ///
+    /// assert_eq!(computer.reverse_continue(), StopReason::Watchpoint {
+    ///     pc: 0x0,
+    ///     address: 0xc,
+    ///     access: Access::Write,
+    ///     old: 0x0,
+    ///     new: 0x7,
+    /// });
+    /// assert_eq!(computer.reverse_continue(), StopReason::StartOfHistory { pc: 0x0 });
+    /// ```
+    pub fn reverse_continue(&mut self) -> StopReason {
+        loop {
+            let undo = match self.undo_step() {
+                Some(undo) => undo,
+                None => return StopReason::StartOfHistory { pc: self.counter },
+            };
+            let pc = self.counter;
+
+            if let Some(store) = undo.store {
+                let triggered = self.watchpoints.iter().any(|watchpoint| {
+                    watchpoint.access.covers(Access::Write) &&
+                    watchpoint.range.contains(&store.address)
+                });
+
+                if triggered {
+                    return StopReason::Watchpoint {
+                        pc,
+                        address: store.address,
+                        access: Access::Write,
+                        old: store.old,
+                        new: store.new,
+                    };
+                }
+            }
+
+            if self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint { pc };
+            }
+        }
+    }
+
+    // Starts the undo record for the next step, taking a checkpoint first if
+    // one is due.
+    fn begin_undo(&mut self) {
+        let checkpoint = match self.history {
+            Some(ref history) if history.needs_checkpoint() => {
+                Some(Checkpoint {
+                    step: history.step,
+                    memory: self.memory.clone(),
+                    counter: self.counter,
+                    state: self.state,
+                    frames: self.frames,
+                    display: (self.display_address,
+                              self.display_width,
+                              self.display_height,
+                              self.display_mode),
+                })
+            }
+            _ => None,
+        };
+
+        if let (Some(checkpoint), Some(history)) = (checkpoint, self.history.as_mut()) {
+            history.push(checkpoint);
+        }
+
+        self.undo = Some(Undo {
+            counter: self.counter,
+            state: self.state,
+            len: self.memory.len(),
+            frames: self.frames,
+            store: None,
+            display: None,
+        });
+    }
+
+    // Reverses the last step and returns its undo record.
+    fn undo_step(&mut self) -> Option<Undo> {
+        let undo = {
+            let history = self.history.as_mut()?;
+            let undo = history.undo.pop();
+            if undo.is_some() {
+                history.step -= 1;
+            }
+            undo
+        };
+
+        let undo = match undo {
+            Some(undo) => undo,
+            None => {
+                self.replay_to_last_step()?;
+                return self.undo_step();
+            }
+        };
+
+        if let Some(store) = undo.store {
+            self.memory.write(store.address, store.old);
+        }
+        if let Some((address, width, height, mode)) = undo.display {
+            self.display_address = address;
+            self.display_width = width;
+            self.display_height = height;
+            self.display_mode = mode;
+        }
+        self.memory.set_len(undo.len);
+        self.redraw = true;
+        self.counter = undo.counter;
+        self.state = undo.state;
+        self.steps -= 1;
+        self.frames = undo.frames;
+
+        Some(undo)
     }
 
-    /// Extracts a slice containing the contents of memory.
+    // Restores the checkpoint before the last step and replays up to where
+    // execution is now, leaving undo records for every replayed step.
+    fn replay_to_last_step(&mut self) -> Option<()> {
+        let (memory, counter, state, frames, display, steps) = {
+            let history = self.history.as_mut()?;
+            let target = history.step.checked_sub(1)?;
+            let index = history.rewind(target)?;
+            let checkpoint = &history.checkpoints[index];
+            let steps = history.step - checkpoint.step;
+
+            history.step = checkpoint.step;
+            history.undo.clear();
+
+            (checkpoint.memory.clone(),
+             checkpoint.counter,
+             checkpoint.state,
+             checkpoint.frames,
+             checkpoint.display,
+             steps)
+        };
+
+        self.memory = memory;
+        self.counter = counter;
+        self.state = state;
+        self.steps -= steps;
+        self.frames = frames;
+        self.display_address = display.0;
+        self.display_width = display.1;
+        self.display_height = display.2;
+        self.display_mode = display.3;
+
+        self.replaying = true;
+        for _ in 0..steps {
+            self.step();
+        }
+        self.replaying = false;
+
+        Some(())
+    }
+
+    /// Executes instructions until the computer stops.
     ///
-    /// # Example
+    /// The computer stops when it halts on `brk`, finds an opcode it doesn't
+    /// understand, or needs a key press that hasn't happened yet. Calling
+    /// `run` again after waiting for input retries the `key` instruction.
+    ///
+    /// Breakpoints and watchpoints also stop the computer. The first
+    /// instruction is always executed, so calling `run` again after stopping
+    /// on a breakpoint continues past it.
+    ///
+    /// # Examples
     ///
     /// ```
-    /// use chifir::computer::Computer;
+    /// use chifir::computer::{Computer, StopReason};
     ///
     /// let mut computer = Computer::new();
     ///
-    /// computer.load_from_slice(&[
-    ///     1, 2, 3, 4
+    /// computer.load(vec![
+    ///     0x10, 0x0, 0x0, 0x0,  // nop
+    ///     0x0, 0x0, 0x0, 0x0,   // brk
     /// ]);
     ///
-    /// assert_eq!([1, 2, 3, 4], computer.dump());
+    /// assert_eq!(computer.run(), StopReason::Halted { pc: 0x4 });
     /// ```
-    pub fn dump(&self) -> &[u32] {
-        self.memory.as_slice()
+    pub fn run(&mut self) -> StopReason {
+        let mut steps: u64 = 0;
+
+        loop {
+            if let Some(reason) = self.run_step(steps) {
+                return reason;
+            }
+            steps = steps.wrapping_add(1);
+        }
     }
 
-    /// Executes the next instruction.
+    /// Executes at most `max_steps` instructions.
+    ///
+    /// Stops early for the same reasons as `run`.
     ///
     /// # Examples
     ///
     /// ```
-    /// use chifir::computer::Computer;
+    /// use chifir::computer::{Computer, StopReason};
     ///
     /// let mut computer = Computer::new();
     ///
     /// computer.load(vec![
-    ///     0x1, 0x3, 0x0, 0x8,  // lpc /3 0 8
-    ///     0x0, 0x0, 0x0, 0x0,  // brk
-    ///     0x2, 0xa, 0x4, 0x0,  // beq /2 4
+    ///     0x1, 0x2, 0x0, 0x0,  // lpc /2
     /// ]);
     ///
-    /// assert_eq!(computer.next(), 0x1);
-    ///
-    /// computer.step();
-    /// computer.step();
-    ///
-    /// assert_eq!(computer.next(), 0x0);
+    /// assert_eq!(computer.run_for(100), StopReason::StepLimit { pc: 0x0 });
     /// ```
-    pub fn step(&mut self) {
-        let counter = self.counter;
-        let opcode = self.fetch(counter);
-        let a = self.fetch(counter + 1);
-        let b = self.fetch(counter + 2);
-        let c = self.fetch(counter + 3);
-        self.exec(opcode, a, b, c);
+    pub fn run_for(&mut self, max_steps: u64) -> StopReason {
+        for steps in 0..max_steps {
+            if let Some(reason) = self.run_step(steps) {
+                return reason;
+            }
+        }
+
+        StopReason::StepLimit { pc: self.counter }
     }
 
-    fn fetch(&mut self, index: u32) -> u32 {
-        let index = index as usize;
+    fn run_step(&mut self, steps: u64) -> Option<StopReason> {
+        let pc = self.counter;
+
+        if steps > 0 && self.breakpoints.contains(&pc) {
+            return Some(StopReason::Breakpoint { pc });
+        }
+
+        self.step();
+
+        if let Some(ref hit) = self.watchpoint_hit {
+            return Some(StopReason::Watchpoint {
+                pc,
+                address: hit.address,
+                access: hit.access,
+                old: hit.old,
+                new: hit.new,
+            });
+        }
 
-        if index >= self.memory.len() {
-            self.memory.resize(index + 1, 0);
+        self.stop_reason()
+    }
+
+    fn stop_reason(&self) -> Option<StopReason> {
+        let pc = self.counter;
+
+        match self.state {
+            State::Running => None,
+            State::Halted => Some(StopReason::Halted { pc }),
+            State::IllegalOpcode(opcode) => Some(StopReason::IllegalOpcode { pc, opcode }),
+            State::WaitingForInput => Some(StopReason::WaitingForInput { pc }),
+            State::TraceFailed => Some(StopReason::TraceFailed { pc }),
+            State::DisplayFailed => Some(StopReason::DisplayFailed { pc }),
         }
+    }
 
-        return self.memory[index];
+    fn fetch(&mut self, index: u32) -> u32 {
+        let value = self.memory.read(index);
+        self.watch(index, Access::Read, value, value);
+        value
     }
 
     fn store(&mut self, index: u32, value: u32) {
-        let index = index as usize;
+        if !self.watchpoints.is_empty() || self.record.is_some() || self.undo.is_some() {
+            let old = self.memory.peek(index);
+            self.watch(index, Access::Write, old, value);
+
+            let store = Store {
+                address: index,
+                old,
+                new: value,
+            };
+
+            if let Some(ref mut record) = self.record {
+                record.store = Some(store);
+            }
+            if let Some(ref mut undo) = self.undo {
+                undo.store = Some(store);
+            }
+        }
+        self.mark_dirty(index);
+        self.memory.write(index, value);
+    }
+
+    // Notes that the band of the display holding `address` needs drawing.
+    fn mark_dirty(&mut self, address: u32) {
+        if !self.redraw && self.display_region().contains(&address) {
+            let row = (address - self.display_address) / self.display_width;
+            self.dirty.insert(row / 6);
+        }
+    }
 
-        if index >= self.memory.len() {
-            self.memory.resize(index + 1, 0);
+    fn watch(&mut self, address: u32, access: Access, old: u32, new: u32) {
+        if self.watchpoint_hit.is_some() || self.replaying {
+            return;
         }
 
-        self.memory[index] = value;
+        let triggered = self.watchpoints.iter().any(|watchpoint| {
+            watchpoint.access.covers(access) && watchpoint.range.contains(&address)
+        });
+
+        if triggered {
+            self.watchpoint_hit = Some(WatchpointHit {
+                address,
+                access,
+                old,
+                new,
+            });
+        }
     }
 
     fn render(&mut self) {
         let width = self.display_width;
         let height = self.display_height;
         let start = self.display_address;
-        let end = start + (width * height);
-        self.fetch(start);
-        self.fetch(end);
-
-        let width = width as usize;
-        let height = height as usize;
-        let start = start as usize;
-        let end = end as usize;
-        let memory = &self.memory[start..end];
-
-        let mut buffer = Cursor::new(Vec::new());
-        write!(buffer,
-               "{}{}{}{}",
-               termion::cursor::Goto(1, 1),
-               sixel::begin(),
-               sixel::from(memory, width, height, false),
-               sixel::end())
-            .unwrap();
-        buffer.flush().unwrap();
-        self.display = buffer.into_inner();
-        self.read_position = 0;
+        let end = start.wrapping_add(width.wrapping_mul(height));
+        self.memory.read(start);
+        self.memory.read(end);
+
+        // Replays only need to leave memory as the original run did.
+        if self.replaying {
+            return;
+        }
 
-        match self.output {
-            Some(ref mut output) => {
-                output.write(self.display.as_slice()).unwrap();
-                output.flush().unwrap();
+        let frame = Frame {
+            width,
+            height,
+            mode: self.display_mode,
+            pixels: self.memory.slice(start, width as usize * height as usize),
+        };
+
+        let shown = match self.display {
+            Some(ref mut display) if self.redraw => display.draw(&frame),
+            Some(ref mut display) => {
+                let bands: Vec<u32> = self.dirty.iter().cloned().collect();
+                display.update(&frame, &bands)
             }
-            None => {}
+            None => Ok(()),
+        };
+        if let Err(error) = shown {
+            self.display = None;
+            self.display_error = Some(error);
+            self.state = State::DisplayFailed;
         }
+        self.dirty.clear();
+        self.redraw = false;
+
+        // The in memory display is only encoded once something reads it.
+        self.frame = Some(frame);
+        self.encoded = None;
+        self.read_position = 0;
     }
 
     fn exec(&mut self, opcode: u32, a: u32, b: u32, c: u32) {
+        self.state = State::Running;
+
         match opcode {
             // Halt execution
-            0 => {}
+            0 => {
+                self.state = State::Halted;
+            }
 
             // PC <- M[A]
             1 => {
@@ -408,11 +1383,7 @@ impl Computer {
             10 => {
                 let b = self.fetch(b);
                 let c = self.fetch(c);
-                if c > 0 {
-                    self.store(a, b / c);
-                } else {
-                    self.store(a, 0);
-                }
+                self.store(a, b.checked_div(c).unwrap_or(0));
                 self.counter += 4;
             }
 
@@ -420,11 +1391,7 @@ impl Computer {
             11 => {
                 let b = self.fetch(b);
                 let c = self.fetch(c);
-                if c > 0 {
-                    self.store(a, b % c);
-                } else {
-                    self.store(a, 0);
-                }
+                self.store(a, b.checked_rem(c).unwrap_or(0));
                 self.counter += 4;
             }
 
@@ -450,35 +1417,59 @@ impl Computer {
 
             // Refresh the screen
             14 => {
+                self.frames += 1;
+                if let Some(ref mut script) = self.script {
+                    script.drawn(self.steps + 1, self.frames);
+                }
                 self.render();
                 self.counter += 4;
             }
 
             // Get one character from the keyboard and store it into M[A]
             15 => {
-                let mut bytes = Vec::new();
-                let mut result = self.keyboard;
-
-                match self.input {
-                    Some(ref mut input) => {
-                        match input.read_to_end(&mut bytes) {
-                            Ok(size) => {
-                                if size > 0 {
-                                    result = Some(bytes[size - 1])
-                                }
+                // Keys read before stepping back are read again, and replays
+                // never wait on the real keyboard.
+                let logged = self.history
+                    .as_ref()
+                    .and_then(|history| history.keys.get(&history.step).cloned());
+
+                let result = if logged.is_some() || self.replaying {
+                    logged
+                } else {
+                    let mut bytes = Vec::new();
+                    let mut result = self.keyboard;
+
+                    if let Some(ref mut input) = self.input {
+                        if let Ok(size) = input.read_to_end(&mut bytes) {
+                            if size > 0 {
+                                result = Some(bytes[size - 1])
                             }
-                            _ => {}
                         }
                     }
-                    _ => {}
-                }
+
+                    if let Some(ref mut script) = self.script {
+                        if let Some(key) = script.press(self.steps, self.frames) {
+                            result = Some(key);
+                        }
+                    }
+
+                    result
+                };
 
                 match result {
                     Some(byte) => {
+                        if let Some(ref mut record) = self.record {
+                            record.key = Some(byte);
+                        }
+                        if let Some(ref mut history) = self.history {
+                            history.keys.insert(history.step, byte);
+                        }
                         self.store(a, byte as u32);
                         self.counter += 4;
                     }
-                    _ => {}
+                    None => {
+                        self.state = State::WaitingForInput;
+                    }
                 }
             }
 
@@ -487,20 +1478,37 @@ impl Computer {
                 self.counter += 4;
             }
 
-            // Configure display at M[A] with width B and height C
+            // Configure display at M[A] with width B and height C, taking the
+            // display mode from the top byte of B
             17 => {
+                if let Some(ref mut undo) = self.undo {
+                    undo.display = Some((self.display_address,
+                                         self.display_width,
+                                         self.display_height,
+                                         self.display_mode));
+                }
                 self.display_address = a;
-                self.display_width = b;
+                self.display_width = b & 0xff_ffff;
                 self.display_height = c;
+                self.display_mode = Mode::from_number(b >> 24);
+                self.redraw = true;
                 self.counter += 4;
             }
 
             // Unknown opcode
-            _ => {}
+            _ => {
+                self.state = State::IllegalOpcode(opcode);
+            }
         }
     }
 }
 
+impl Default for Computer {
+    fn default() -> Self {
+        Computer::new()
+    }
+}
+
 impl Write for Computer {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match buf.last() {
@@ -524,8 +1532,13 @@ impl Read for Computer {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         use std::cmp;
 
-        let amt = cmp::min(self.display.len() - self.read_position, buf.len());
-        let a = &self.display[self.read_position..(self.read_position + amt)];
+        if self.encoded.is_none() {
+            self.encoded = Some(self.frame.as_ref().map(display::encode).unwrap_or_default());
+        }
+        let encoded = self.encoded.as_ref().unwrap();
+
+        let amt = cmp::min(encoded.len() - self.read_position, buf.len());
+        let a = &encoded[self.read_position..(self.read_position + amt)];
         buf[..amt].copy_from_slice(a);
         self.read_position += amt;
         Ok(amt)
@@ -534,8 +1547,65 @@ impl Read for Computer {
 
 #[cfg(test)]
 mod tests {
-    use super::Computer;
-    use std::io::{Read, Cursor};
+    use super::{Access, Computer, State, StopReason};
+    use display::{self, Display, Frame, Mode};
+    use script::Script;
+    use trace::{Record, Store, TraceReader};
+    use std::io::{self, BufWriter, Read, Write, Cursor};
+    use std::sync::{Arc, Mutex};
+
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Takes the given number of bytes, then fails every write.
+    struct FullDisk(usize);
+
+    impl Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() > self.0 {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"));
+            }
+            self.0 -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct SharedFrames(Arc<Mutex<Vec<Frame>>>);
+
+    impl Display for SharedFrames {
+        fn draw(&mut self, frame: &Frame) -> io::Result<()> {
+            self.0.lock().unwrap().push(frame.clone());
+            Ok(())
+        }
+    }
+
+    // Records the bands of every update, and None for every full frame.
+    struct SharedUpdates(Arc<Mutex<Vec<Option<Vec<u32>>>>>);
+
+    impl Display for SharedUpdates {
+        fn draw(&mut self, _frame: &Frame) -> io::Result<()> {
+            self.0.lock().unwrap().push(None);
+            Ok(())
+        }
+
+        fn update(&mut self, _frame: &Frame, bands: &[u32]) -> io::Result<()> {
+            self.0.lock().unwrap().push(Some(bands.to_vec()));
+            Ok(())
+        }
+    }
 
     #[test]
     fn it_runs_opcode_0() {
@@ -645,11 +1715,11 @@ mod tests {
     fn it_prevents_overflow_when_running_opcode_7() {
         // M[A] <- M[B] + M[C]
         let mut m = Computer::new();
-        m.load_from_slice(&[7, 4, 5, 6, 1, u32::max_value(), 1]);
+        m.load_from_slice(&[7, 4, 5, 6, 1, u32::MAX, 1]);
 
         assert_eq!(0, m.counter);
         m.step();
-        assert_eq!([7, 4, 5, 6, 0, u32::max_value(), 1], m.dump());
+        assert_eq!([7, 4, 5, 6, 0, u32::MAX, 1], m.dump());
         assert_eq!(4, m.counter);
     }
 
@@ -693,11 +1763,11 @@ mod tests {
     fn it_prevents_overflow_when_running_opcode_9() {
         // M[A] <- M[B] * M[C]
         let mut m = Computer::new();
-        m.load_from_slice(&[9, 4, 5, 6, 0, u32::max_value(), 2]);
+        m.load_from_slice(&[9, 4, 5, 6, 0, u32::MAX, 2]);
 
         assert_eq!(0, m.counter);
         m.step();
-        assert_eq!([9, 4, 5, 6, 4294967294, u32::max_value(), 2], m.dump());
+        assert_eq!([9, 4, 5, 6, 4294967294, u32::MAX, 2], m.dump());
         assert_eq!(4, m.counter);
     }
 
@@ -862,6 +1932,248 @@ mod tests {
         assert_eq!(100, m.display_address);
         assert_eq!(640, m.display_width);
         assert_eq!(480, m.display_height);
+        assert_eq!(Mode::Monochrome, m.display_mode);
+    }
+
+    #[test]
+    fn it_only_updates_the_bands_written_since_the_last_frame() {
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let mut m = Computer::new().display(Box::new(SharedUpdates(updates.clone()))).history(4, 8);
+        m.load_from_slice(&[17, 0x40, 2, 0x14,    // cfv 40 2 14
+                            14, 0, 0, 0,          // drw
+                            4, 0x59, 0x3c, 0,     // lea 59 3c
+                            4, 0x4e, 0x3c, 0,     // lea 4e 3c
+                            14, 0, 0, 0,          // drw
+                            14, 0, 0, 0,          // drw
+                            17, 0x40, 2, 0x14,    // cfv 40 2 14
+                            14, 0, 0, 0,          // drw
+                            0, 0, 0, 0]);         // brk
+        m.poke(0x3c, 1);
+        m.run();
+
+        assert_eq!(vec![None, Some(vec![1, 2]), Some(vec![]), None], *updates.lock().unwrap());
+
+        m.poke(0x40, 1);
+        m.counter = 0x10;
+        m.run_for(1);
+        assert_eq!(Some(&Some(vec![0])), updates.lock().unwrap().last());
+
+        assert!(m.step_back());
+        m.run_for(1);
+        assert_eq!(Some(&None), updates.lock().unwrap().last());
+    }
+
+    #[test]
+    fn it_takes_the_display_mode_from_the_top_byte_of_the_width() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let mut m = Computer::new().display(Box::new(SharedFrames(frames.clone())));
+        m.load_from_slice(&[17, 8, 0x0300_0002, 1,  // cfv 8 3000002 1
+                            14, 0, 0, 0,            // drw
+                            0xff0000, 0x00ff00]);
+        m.run_for(2);
+
+        assert_eq!(2, m.display_width);
+        assert_eq!(8..10, m.display_region());
+        assert_eq!(vec![Frame {
+                            width: 2,
+                            height: 1,
+                            mode: Mode::Rgb,
+                            pixels: vec![0xff0000, 0x00ff00],
+                        }],
+                   *frames.lock().unwrap());
+
+        let mut sixel = String::new();
+        m.read_to_string(&mut sixel).unwrap();
+        assert!(sixel.contains("#0;2;100;0;0#1;2;0;100;0#0@?$#1?@$-"));
+    }
+
+    #[test]
+    fn it_halts_when_running_opcode_0() {
+        let mut m = Computer::new();
+        m.load_from_slice(&[16, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(StopReason::Halted { pc: 4 }, m.run());
+        assert_eq!(State::Halted, m.state());
+    }
+
+    #[test]
+    fn it_stops_on_unknown_opcodes() {
+        let mut m = Computer::new();
+        m.load_from_slice(&[16, 0, 0, 0, 99, 0, 0, 0]);
+
+        assert_eq!(StopReason::IllegalOpcode { pc: 4, opcode: 99 }, m.run());
+        assert_eq!(State::IllegalOpcode(99), m.state());
+    }
+
+    #[test]
+    fn it_stops_when_waiting_for_input() {
+        let mut m = Computer::new();
+        m.load_from_slice(&[15, 8, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(StopReason::WaitingForInput { pc: 0 }, m.run());
+        assert_eq!(State::WaitingForInput, m.state());
+
+        m.write_all(&[0x61]).unwrap();
+
+        assert_eq!(StopReason::Halted { pc: 4 }, m.run());
+        assert_eq!(0x61, m.memory.peek(8));
+    }
+
+    #[test]
+    fn it_stops_after_the_maximum_number_of_steps() {
+        let mut m = Computer::new();
+        m.load_from_slice(&[16, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(StopReason::StepLimit { pc: 4 }, m.run_for(1));
+        assert_eq!(State::Running, m.state());
+        assert_eq!(StopReason::Halted { pc: 8 }, m.run_for(5));
+    }
+
+    #[test]
+    fn it_stops_on_breakpoints() {
+        let mut m = Computer::new();
+        m.load_from_slice(&[16, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0]);
+        m.set_breakpoint(4);
+
+        assert_eq!(StopReason::Breakpoint { pc: 4 }, m.run());
+        assert_eq!(4, m.counter);
+        assert_eq!(StopReason::Halted { pc: 8 }, m.run());
+    }
+
+    #[test]
+    fn it_clears_breakpoints() {
+        let mut m = Computer::new();
+        m.load_from_slice(&[16, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0]);
+        m.set_breakpoint(4);
+
+        assert!(m.clear_breakpoint(4));
+        assert!(!m.clear_breakpoint(4));
+        assert_eq!(StopReason::Halted { pc: 8 }, m.run());
+    }
+
+    #[test]
+    fn it_stops_on_read_watchpoints() {
+        // M[A] <- M[B] + M[C]
+        let mut m = Computer::new();
+        m.load_from_slice(&[7, 8, 9, 10, 0, 0, 0, 0, 0, 11, 2]);
+        m.set_watchpoint(10..11, Access::Read);
+
+        assert_eq!(StopReason::Watchpoint {
+                       pc: 0,
+                       address: 10,
+                       access: Access::Read,
+                       old: 2,
+                       new: 2,
+                   },
+                   m.run());
+    }
+
+    #[test]
+    fn it_ignores_instruction_fetches_for_watchpoints() {
+        let mut m = Computer::new();
+        m.load_from_slice(&[16, 0, 0, 0, 0, 0, 0, 0]);
+        m.set_watchpoint(0..8, Access::ReadWrite);
+
+        assert_eq!(StopReason::Halted { pc: 4 }, m.run());
+    }
+
+    #[test]
+    fn it_stops_on_writes_to_the_display() {
+        // Configure display at M[A] with width B and height C
+        // M[M[B]] <- M[A]
+        let mut m = Computer::new();
+        m.load_from_slice(&[17, 100, 4, 4, 6, 12, 13, 0, 0, 0, 0, 0, 1, 105]);
+        m.step();
+        let region = m.display_region();
+        m.set_watchpoint(region, Access::Write);
+
+        assert_eq!(StopReason::Watchpoint {
+                       pc: 4,
+                       address: 105,
+                       access: Access::Write,
+                       old: 0,
+                       new: 1,
+                   },
+                   m.run());
+        assert!(m.clear_watchpoint(100..116));
+        assert_eq!(StopReason::Halted { pc: 8 }, m.run());
+    }
+
+    #[test]
+    fn it_records_a_trace_of_executed_instructions() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let input = Box::new(Cursor::new(vec![0x61]));
+
+        let mut m = Computer::new().input(input).trace(Box::new(SharedBuffer(trace.clone()))).unwrap();
+        m.load_from_slice(&[15, 8, 0, 0, 0, 0, 0, 0, 9]);
+        m.run();
+
+        let bytes = trace.lock().unwrap().clone();
+        let records: Vec<Record> = TraceReader::new(Cursor::new(bytes))
+            .unwrap()
+            .map(|record| record.unwrap())
+            .collect();
+
+        assert_eq!(vec![Record {
+                            pc: 0,
+                            opcode: 15,
+                            a: 8,
+                            b: 0,
+                            c: 0,
+                            store: Some(Store {
+                                address: 8,
+                                old: 9,
+                                new: 0x61,
+                            }),
+                            key: Some(0x61),
+                        },
+                        Record {
+                            pc: 4,
+                            opcode: 0,
+                            a: 0,
+                            b: 0,
+                            c: 0,
+                            store: None,
+                            key: None,
+                        }],
+                   records);
+    }
+
+    #[test]
+    fn it_stops_when_the_trace_cant_be_written() {
+        assert!(Computer::new().trace(Box::new(FullDisk(3))).is_err());
+
+        // The header fits, but the first record doesn't.
+        let mut m = Computer::new().trace(Box::new(FullDisk(5))).unwrap();
+        m.load_from_slice(&[16, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(StopReason::TraceFailed { pc: 4 }, m.run());
+        assert_eq!(State::TraceFailed, m.state());
+        assert_eq!(io::ErrorKind::StorageFull, m.trace_error().unwrap().kind());
+
+        // Tracing is off from then on.
+        assert_eq!(StopReason::Halted { pc: 8 }, m.run());
+
+        // Buffered writers fail when the trace is flushed at the end.
+        let mut m = Computer::new().trace(Box::new(BufWriter::new(FullDisk(5)))).unwrap();
+        m.load_from_slice(&[16, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(StopReason::TraceFailed { pc: 8 }, m.run());
+    }
+
+    #[test]
+    fn it_stops_when_the_display_cant_show_a_frame() {
+        let mut m = Computer::new().output(Box::new(FullDisk(0)));
+        m.load_from_slice(&[14, 0, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(StopReason::DisplayFailed { pc: 4 }, m.run());
+        assert_eq!(State::DisplayFailed, m.state());
+        assert_eq!(io::ErrorKind::StorageFull, m.display_error().unwrap().kind());
+
+        // The display is unbound, but frames are still kept in memory.
+        assert_eq!(StopReason::Halted { pc: 8 }, m.run());
+        assert_eq!(2, m.frames());
+        assert!(m.frame().is_some());
     }
 
     #[test]
@@ -873,4 +2185,200 @@ mod tests {
         m.step();
         assert_eq!([1, 2, 4, 0, 0, 0, 0, 0], m.dump());
     }
+
+    #[test]
+    fn it_keeps_dumps_small_after_reading_high_addresses() {
+        // M[A] <- M[B], then draw a display near the top of memory
+        let mut m = Computer::new();
+        m.load_from_slice(&[4, 0xc, 0xFFFF_FFF0, 0, 17, 0xFFFF_FF00, 16, 16, 14, 0, 0, 0]);
+
+        m.step();
+        m.step();
+        m.step();
+        assert_eq!(12, m.counter);
+        assert_eq!(0xd, m.dump().len());
+    }
+
+    #[test]
+    fn it_allocates_memory_sparsely_when_stepping() {
+        // M[M[B]] <- M[A]
+        let mut m = Computer::new();
+        m.load_from_slice(&[6, 4, 5, 0, 7, 0xFFFF_FFF0]);
+
+        m.step();
+        assert_eq!(7, m.memory.peek(0xFFFF_FFF0));
+        assert_eq!(1, m.memory.pages());
+        assert_eq!([6, 4, 5, 0, 7, 0xFFFF_FFF0], m.dump());
+    }
+
+    #[test]
+    fn it_renders_displays_at_high_addresses() {
+        // Configure display at M[A] with width B and height C
+        let mut m = Computer::new();
+        m.load_from_slice(&[17, 0xFFFF_FF00, 16, 16, 14, 0, 0, 0]);
+
+        m.step();
+        m.step();
+        assert_eq!(8, m.counter);
+        assert_eq!(0, m.memory.pages());
+    }
+
+    fn history_program() -> Vec<u32> {
+        let mut program = vec![17, 0x40, 2, 2,  // cfv 40 2 2
+                               15, 0x20, 0, 0,  // key 20
+                               7, 0x21, 0x21, 0x22,  // add 21 21 22
+                               14, 0, 0, 0,  // drw
+                               1, 0x24, 0, 0];  // lpc /2 8
+        program.resize(0x25, 0);
+        program[0x22] = 1;
+        program[0x24] = 8;
+        program
+    }
+
+    #[test]
+    fn it_steps_back_to_exactly_the_previous_state() {
+        let input = Box::new(Cursor::new(vec![0x61]));
+        let mut m = Computer::new().input(input).history(3, 8);
+        m.load(history_program());
+
+        let mut states = Vec::new();
+        for _ in 0..20 {
+            states.push((m.dump().to_vec(), m.counter(), m.state(), m.display_region()));
+            m.step();
+        }
+
+        while let Some(state) = states.pop() {
+            assert!(m.step_back());
+            assert_eq!(state, (m.dump().to_vec(), m.counter(), m.state(), m.display_region()));
+        }
+        assert!(!m.step_back());
+    }
+
+    #[test]
+    fn it_reads_the_same_keys_after_stepping_back() {
+        let input = Box::new(Cursor::new(vec![0x61]));
+        let mut m = Computer::new().input(input).history(3, 4);
+        m.load(history_program());
+
+        m.run_for(10);
+        while m.step_back() {}
+
+        m.run_for(2);
+        assert_eq!(0x61, m.peek(0x20));
+    }
+
+    #[test]
+    fn it_forgets_history_past_the_oldest_checkpoint() {
+        let mut m = Computer::new().history(2, 2);
+        m.load(vec![1, 2, 0, 0]);  // lpc /2 0
+
+        m.run_for(10);
+        let mut steps = 0;
+        while m.step_back() {
+            steps += 1;
+        }
+
+        assert_eq!(4, steps);
+    }
+
+    #[test]
+    fn it_reverse_continues_to_breakpoints() {
+        let mut m = Computer::new().history(3, 4);
+        m.load(history_program());
+        m.write_all(&[0x61]).unwrap();
+
+        m.run_for(12);
+        m.set_breakpoint(0x4);
+
+        assert_eq!(StopReason::Breakpoint { pc: 0x4 }, m.reverse_continue());
+        assert_eq!(StopReason::StartOfHistory { pc: 0x0 }, m.reverse_continue());
+    }
+
+    #[test]
+    fn it_restores_snapshots() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut m = Computer::new();
+        m.load_from_slice(&[17, 0x40, 2, 2, 14, 0, 0, 0, 15, 0x20, 0, 0]);
+        m.write_all(&[0x61]).unwrap();
+        m.run();
+
+        let snapshot = m.snapshot();
+        let mut restored = Computer::new().output(Box::new(SharedBuffer(output.clone())));
+        restored.restore(&snapshot);
+
+        assert_eq!(snapshot, restored.snapshot());
+        assert_eq!(0x40..0x44, restored.display_region());
+        assert_eq!(display::encode(snapshot.frame.as_ref().unwrap()),
+                   *output.lock().unwrap());
+
+        assert_eq!(StopReason::Halted { pc: 0xc }, restored.run());
+        assert_eq!(0x61, restored.peek(0x20));
+    }
+
+    #[test]
+    fn it_gives_identical_runs_with_a_script() {
+        let run = || {
+            let output = Arc::new(Mutex::new(Vec::new()));
+            let script = Script::parse("step 0 61\nframe 1 62\nframe 2 63\nframe 2 64")
+                .unwrap();
+            let mut m = Computer::new()
+                .script(script)
+                .output(Box::new(SharedBuffer(output.clone())));
+            m.load_from_slice(&[15, 0x10, 0, 0, 14, 0, 0, 0, 1, 0x11, 0, 0]);
+
+            assert_eq!(StopReason::WaitingForInput { pc: 0 }, m.run());
+            assert_eq!(0, m.pending_keys());
+
+            let output = output.lock().unwrap().clone();
+            (m.peek(0x10), m.steps(), m.frames(), output)
+        };
+
+        let (key, steps, frames, output) = run();
+
+        assert_eq!(0x64, key);
+        assert_eq!(10, steps);
+        assert_eq!(3, frames);
+        assert_eq!((key, steps, frames, output), run());
+    }
+
+    #[test]
+    fn it_reads_the_scripted_key_that_was_pressed_last() {
+        let script = Script::parse("frame 1 61\nstep 2 62").unwrap();
+        let mut m = Computer::new().script(script);
+        m.load_from_slice(&[16, 0, 0, 0, 16, 0, 0, 0, 16, 0, 0, 0, 14, 0, 0, 0, 15, 0x18, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(StopReason::Halted { pc: 0x14 }, m.run());
+        assert_eq!(0x61, m.peek(0x18));
+    }
+
+    #[test]
+    fn it_counts_steps_and_frames_backwards() {
+        let mut m = Computer::new().history(3, 8);
+        m.load(history_program());
+        m.write_all(&[0x61]).unwrap();
+
+        m.run_for(10);
+        assert_eq!((10, 3), (m.steps(), m.frames()));
+
+        for _ in 0..5 {
+            m.step_back();
+        }
+        assert_eq!((5, 1), (m.steps(), m.frames()));
+    }
+
+    #[test]
+    fn it_draws_frames_on_the_bound_display() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let mut m = Computer::new().display(Box::new(SharedFrames(frames.clone())));
+        m.load_from_slice(&[17, 12, 3, 1, 14, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1]);
+        m.run();
+
+        assert_eq!(vec![Frame {
+                            width: 3,
+                            height: 1,
+                            mode: Mode::Monochrome,
+                            pixels: vec![1, 0, 1],
+                        }],
+                   *frames.lock().unwrap());
+    }
 }
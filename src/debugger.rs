@@ -0,0 +1,637 @@
+//! An interactive debugger for Chifir programs.
+//!
+//! The debugger wraps a `Computer` along with the labels found while
+//! compiling its program. It takes commands one line at a time and returns
+//! the text to show in response. Anywhere an address is expected, a label
+//! name can be used instead, optionally followed by a hex offset like
+//! `font-a+4`.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//! use chifir::computer::Computer;
+//! use chifir::debugger::Debugger;
+//!
+//! let mut compiler = Compiler::new();
+//!
+//! write!(compiler, "{}","
+//! loop:
+//!   add x x one
+//!   lpc /2 loop
+//! x:
+//!   0
+//! one:
+//!   1
+//! ").unwrap();
+//!
+//! let mut computer = Computer::new();
+//! computer.load_from_slice(compiler.compile().unwrap());
+//!
+//! let mut debugger = Debugger::new(computer, compiler.labels().clone());
+//!
+//! debugger.execute("break loop");
+//! debugger.execute("continue");
+//! debugger.execute("continue");
+//!
+//! assert_eq!("00000008 <x>: 00000002", debugger.execute("print x"));
+//! ```
+//!
+//! # Table 1
+//!
+//! A complete list of all debugger commands.
+//!
+//! |Command                     |Short|Description                                  |
+//! |:---------------------------|:----|:--------------------------------------------|
+//! |`step [count]`              |`s`  |Execute one or more instructions             |
+//! |`continue`                  |`c`  |Run until the computer stops                 |
+//! |`reverse-step [count]`      |`rs` |Step backwards through instructions          |
+//! |`reverse-continue`          |`rc` |Run backwards to a breakpoint or watchpoint  |
+//! |`break [address]`           |`b`  |Set a breakpoint, or list all breakpoints    |
+//! |`delete address`            |     |Remove a breakpoint                          |
+//! |`watch [range] [r\|w\|rw]`  |`w`  |Set a watchpoint, or list all watchpoints    |
+//! |`unwatch range`             |     |Remove a watchpoint                          |
+//! |`print address [count]`     |`p`  |Show memory                                  |
+//! |`set address value`         |     |Change memory                                |
+//! |`pc`                        |     |Show the program counter                     |
+//! |`disassemble [address] [n]` |`d`  |Show instructions, around the PC by default  |
+//! |`key value`                 |     |Press a key                                  |
+//! |`save file`                 |     |Save a snapshot of the computer              |
+//! |`restore file`              |     |Restore a snapshot saved with `save`         |
+//! |`help`                      |`h`  |Show a list of commands                      |
+//!
+//! Reverse execution needs a computer that records history, see
+//! `Computer::history`.
+//!
+//! A range is an address, two addresses like `display..display+100`, or the
+//! word `display` for the memory the display is configured to show.
+
+use super::compiler;
+use super::computer::{Access, Computer, StopReason};
+use super::snapshot::Snapshot;
+use super::symbols::Symbols;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Range;
+
+const HELP: &str = "\
+step [count]               Execute one or more instructions
+continue                   Run until the computer stops
+reverse-step [count]       Step backwards through instructions
+reverse-continue           Run backwards to a breakpoint or watchpoint
+break [address]            Set a breakpoint, or list all breakpoints
+delete address             Remove a breakpoint
+watch [range] [r|w|rw]     Set a watchpoint, or list all watchpoints
+unwatch range              Remove a watchpoint
+print address [count]      Show memory
+set address value          Change memory
+pc                         Show the program counter
+disassemble [address] [n]  Show instructions, around the PC by default
+key value                  Press a key
+save file                  Save a snapshot of the computer
+restore file               Restore a snapshot saved with `save`
+help                       Show this list";
+
+pub struct Debugger {
+    computer: Computer,
+    symbols: Symbols,
+}
+
+impl Debugger {
+    /// Create a new `Debugger` for `computer`.
+    ///
+    /// The `labels` are used for looking up addresses by name and for
+    /// annotating addresses in the output.
+    pub fn new(computer: Computer, labels: HashMap<String, u32>) -> Self {
+        Debugger {
+            computer,
+            symbols: Symbols::new(labels),
+        }
+    }
+
+    /// Returns the computer being debugged.
+    pub fn computer(&self) -> &Computer {
+        &self.computer
+    }
+
+    /// Returns the computer being debugged, so it can be changed.
+    pub fn computer_mut(&mut self) -> &mut Computer {
+        &mut self.computer
+    }
+
+    /// Runs a single debugger command and returns its output.
+    ///
+    /// Problems with the command, like unknown labels, are reported in the
+    /// output rather than as errors.
+    pub fn execute(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+
+        let result = match words.split_first() {
+            Some((&name, args)) => {
+                match name {
+                    "step" | "s" => self.step(args),
+                    "continue" | "c" => self.resume(args),
+                    "reverse-step" | "rs" => self.step_back(args),
+                    "reverse-continue" | "rc" => self.reverse_continue(args),
+                    "break" | "b" => self.set_breakpoint(args),
+                    "delete" => self.clear_breakpoint(args),
+                    "watch" | "w" => self.set_watchpoint(args),
+                    "unwatch" => self.clear_watchpoint(args),
+                    "print" | "p" => self.print(args),
+                    "set" => self.set(args),
+                    "pc" => Ok(self.instruction(self.computer.counter())),
+                    "disassemble" | "d" => self.disassemble(args),
+                    "key" => self.key(args),
+                    "save" => self.save(args),
+                    "restore" => self.restore(args),
+                    "help" | "h" => Ok(HELP.to_string()),
+                    _ => Err(format!("unknown command `{}`, try `help`", name)),
+                }
+            }
+            None => Ok(String::new()),
+        };
+
+        match result {
+            Ok(output) => output,
+            Err(error) => error,
+        }
+    }
+
+    fn step(&mut self, args: &[&str]) -> Result<String, String> {
+        let count = match args.first() {
+            Some(count) => self.parse_number(count)?,
+            None => 1,
+        };
+
+        for _ in 0..count {
+            match self.computer.run_for(1) {
+                StopReason::StepLimit { .. } => {}
+                reason => return Ok(self.stopped(reason)),
+            }
+        }
+
+        Ok(self.instruction(self.computer.counter()))
+    }
+
+    fn resume(&mut self, _args: &[&str]) -> Result<String, String> {
+        let reason = self.computer.run();
+        Ok(self.stopped(reason))
+    }
+
+    fn step_back(&mut self, args: &[&str]) -> Result<String, String> {
+        let count = match args.first() {
+            Some(count) => self.parse_number(count)?,
+            None => 1,
+        };
+
+        for _ in 0..count {
+            if !self.computer.step_back() {
+                let pc = self.computer.counter();
+                return Ok(self.stopped(StopReason::StartOfHistory { pc }));
+            }
+        }
+
+        Ok(self.instruction(self.computer.counter()))
+    }
+
+    fn reverse_continue(&mut self, _args: &[&str]) -> Result<String, String> {
+        let reason = self.computer.reverse_continue();
+        Ok(self.stopped(reason))
+    }
+
+    fn set_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        match args.first() {
+            Some(address) => {
+                let address = self.parse_address(address)?;
+                self.computer.set_breakpoint(address);
+                Ok(format!("breakpoint at {}", self.describe(address)))
+            }
+            None => {
+                let breakpoints = self.computer.breakpoints();
+                if breakpoints.is_empty() {
+                    return Ok("no breakpoints".to_string());
+                }
+
+                let lines: Vec<String> = breakpoints.iter()
+                    .map(|address| format!("breakpoint at {}", self.describe(*address)))
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+        }
+    }
+
+    fn clear_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let address = self.parse_address(args.first().ok_or("delete needs an address")?)?;
+
+        if self.computer.clear_breakpoint(address) {
+            Ok(format!("deleted breakpoint at {}", self.describe(address)))
+        } else {
+            Err(format!("no breakpoint at {}", self.describe(address)))
+        }
+    }
+
+    fn set_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        match args.first() {
+            Some(range) => {
+                let range = self.parse_range(range)?;
+                let access = match args.get(1) {
+                    Some(&"r") => Access::Read,
+                    Some(&"w") | None => Access::Write,
+                    Some(&"rw") => Access::ReadWrite,
+                    Some(access) => return Err(format!("unknown access `{}`", access)),
+                };
+
+                let description = self.describe_watchpoint(&range, access);
+                self.computer.set_watchpoint(range, access);
+                Ok(description)
+            }
+            None => {
+                let watchpoints = self.computer.watchpoints();
+                if watchpoints.is_empty() {
+                    return Ok("no watchpoints".to_string());
+                }
+
+                let lines: Vec<String> = watchpoints.iter()
+                    .map(|watchpoint| {
+                        self.describe_watchpoint(&watchpoint.range, watchpoint.access)
+                    })
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+        }
+    }
+
+    fn clear_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let range = self.parse_range(args.first().ok_or("unwatch needs a range")?)?;
+
+        if self.computer.clear_watchpoint(range.clone()) {
+            Ok(format!("deleted watchpoint on {:08x}..{:08x}", range.start, range.end))
+        } else {
+            Err(format!("no watchpoint on {:08x}..{:08x}", range.start, range.end))
+        }
+    }
+
+    fn print(&mut self, args: &[&str]) -> Result<String, String> {
+        let address = self.parse_address(args.first().ok_or("print needs an address")?)?;
+        let count = match args.get(1) {
+            Some(count) => self.parse_number(count)?,
+            None => 1,
+        };
+
+        let mut lines = Vec::new();
+        let mut offset = 0;
+
+        while offset < count {
+            let start = address.wrapping_add(offset);
+            let words: Vec<String> = (offset..count.min(offset + 4))
+                .map(|index| format!("{:08x}", self.computer.peek(address.wrapping_add(index))))
+                .collect();
+            lines.push(format!("{}: {}", self.describe(start), words.join(" ")));
+            offset += 4;
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    fn set(&mut self, args: &[&str]) -> Result<String, String> {
+        let address = self.parse_address(args.first().ok_or("set needs an address")?)?;
+        let value = self.parse_address(args.get(1).ok_or("set needs a value")?)?;
+
+        self.computer.poke(address, value);
+        Ok(format!("{}: {:08x}", self.describe(address), value))
+    }
+
+    fn disassemble(&mut self, args: &[&str]) -> Result<String, String> {
+        let counter = self.computer.counter();
+        let start = match args.first() {
+            Some(address) => self.parse_address(address)?,
+            None => counter.saturating_sub(8),
+        };
+        let count = match args.get(1) {
+            Some(count) => self.parse_number(count)?,
+            None => 5,
+        };
+
+        let lines: Vec<String> = (0..count)
+            .map(|index| self.instruction(start.wrapping_add(index * 4)))
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    fn key(&mut self, args: &[&str]) -> Result<String, String> {
+        let key = self.parse_number(args.first().ok_or("key needs a value")?)?;
+        if key > 0xff {
+            return Err(format!("key `{:x}` doesn't fit in a byte", key));
+        }
+
+        self.computer.write_all(&[key as u8]).map_err(|error| error.to_string())?;
+        Ok(format!("pressed key {:02x}", key))
+    }
+
+    fn save(&mut self, args: &[&str]) -> Result<String, String> {
+        let path = args.first().ok_or("save needs a file name")?;
+
+        File::create(path)
+            .and_then(|file| self.computer.snapshot().write_to(BufWriter::new(file)))
+            .map_err(|error| format!("{}: {}", path, error))?;
+        Ok(format!("saved snapshot to {}", path))
+    }
+
+    fn restore(&mut self, args: &[&str]) -> Result<String, String> {
+        let path = args.first().ok_or("restore needs a file name")?;
+
+        let snapshot = File::open(path)
+            .and_then(|file| Snapshot::read_from(BufReader::new(file)))
+            .map_err(|error| format!("{}: {}", path, error))?;
+        self.computer.restore(&snapshot);
+        Ok(format!("restored snapshot from {}\n{}",
+                   path,
+                   self.instruction(self.computer.counter())))
+    }
+
+    fn stopped(&self, reason: StopReason) -> String {
+        let message = match reason {
+            StopReason::Halted { .. } => "halted".to_string(),
+            StopReason::IllegalOpcode { opcode, .. } => format!("illegal opcode {:x}", opcode),
+            StopReason::WaitingForInput { .. } => {
+                "waiting for input, use `key` to press a key".to_string()
+            }
+            StopReason::StepLimit { .. } => "stopped".to_string(),
+            StopReason::Breakpoint { .. } => "breakpoint".to_string(),
+            StopReason::Watchpoint { pc, address, access, old, new } => {
+                let access = match access {
+                    Access::Read => "read from",
+                    _ => "write to",
+                };
+                format!("watchpoint, {} {} by {}: {:08x} -> {:08x}",
+                        access,
+                        self.describe(address),
+                        self.describe(pc),
+                        old,
+                        new)
+            }
+            StopReason::StartOfHistory { .. } => "start of history".to_string(),
+            StopReason::TraceFailed { .. } => {
+                match self.computer.trace_error() {
+                    Some(error) => format!("trace failed: {}", error),
+                    None => "trace failed".to_string(),
+                }
+            }
+            StopReason::DisplayFailed { .. } => {
+                match self.computer.display_error() {
+                    Some(error) => format!("display failed: {}", error),
+                    None => "display failed".to_string(),
+                }
+            }
+        };
+
+        format!("{}\n{}", message, self.instruction(self.computer.counter()))
+    }
+
+    // Formats the instruction at `address`, marking it if it's the next one
+    // to be executed. Operands that match a label get a comment with its name.
+    fn instruction(&self, address: u32) -> String {
+        let marker = if address == self.computer.counter() {
+            "=>"
+        } else {
+            "  "
+        };
+
+        let opcode = self.computer.peek(address);
+        let opcode = match compiler::mnemonic(opcode) {
+            Some(mnemonic) => mnemonic.to_string(),
+            None => format!("{:x}", opcode),
+        };
+
+        let operands: Vec<u32> = (1..4)
+            .map(|offset| self.computer.peek(address.wrapping_add(offset)))
+            .collect();
+
+        let mut names: Vec<&str> = Vec::new();
+        for name in operands.iter().filter(|operand| **operand != 0).filter_map(|operand| {
+            self.symbols.label_at(*operand)
+        }) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        let mut text = format!("{} {:08x} {:<24} {} {:x} {:x} {:x}",
+                               marker,
+                               address,
+                               self.symbols.label_at(address).map(|label| format!("<{}>", label))
+                                   .unwrap_or_default(),
+                               opcode,
+                               operands[0],
+                               operands[1],
+                               operands[2]);
+
+        if !names.is_empty() {
+            text.push_str(&format!("  ; {}", names.join(", ")));
+        }
+
+        text
+    }
+
+    fn describe_watchpoint(&self, range: &Range<u32>, access: Access) -> String {
+        let access = match access {
+            Access::Read => "r",
+            Access::Write => "w",
+            Access::ReadWrite => "rw",
+        };
+
+        format!("watchpoint ({}) on {:08x}..{:08x}", access, range.start, range.end)
+    }
+
+    fn parse_range(&self, range: &str) -> Result<Range<u32>, String> {
+        if range == "display" {
+            return Ok(self.computer.display_region());
+        }
+
+        match range.find("..") {
+            Some(index) => {
+                let start = self.parse_address(&range[..index])?;
+                let end = self.parse_address(&range[index + 2..])?;
+                Ok(start..end)
+            }
+            None => {
+                let start = self.parse_address(range)?;
+                Ok(start..start.saturating_add(1))
+            }
+        }
+    }
+
+    fn parse_address(&self, address: &str) -> Result<u32, String> {
+        self.symbols.resolve(address)
+    }
+
+    fn describe(&self, address: u32) -> String {
+        self.symbols.describe(address)
+    }
+
+    fn parse_number(&self, number: &str) -> Result<u32, String> {
+        u32::from_str_radix(number, 16).map_err(|_| format!("invalid number `{}`", number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use compiler::Compiler;
+    use computer::Computer;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    fn debugger() -> Debugger {
+        let mut compiler = Compiler::new();
+        compiler.write_all(b"
+        cfv display 4 4
+
+        loop:
+          add x x one
+          sra one x
+          lpc /2 loop
+
+        x:
+          17
+        one:
+          1
+        display:
+          0
+        ")
+            .unwrap();
+
+        let mut computer = Computer::new().history(4, 8);
+        computer.load_from_slice(compiler.compile().unwrap());
+
+        Debugger::new(computer, compiler.labels().clone())
+    }
+
+    #[test]
+    fn it_steps_through_instructions() {
+        let mut d = debugger();
+
+        assert_eq!("=> 00000008                          sra 14 10 0  ; one, x",
+                   d.execute("step 2"));
+        assert_eq!(8, d.computer().counter());
+    }
+
+    #[test]
+    fn it_stops_on_breakpoints_set_by_label() {
+        let mut d = debugger();
+
+        assert_eq!("breakpoint at 00000004 <loop>", d.execute("break loop"));
+        assert_eq!("breakpoint\n=> 00000004 <loop>                   add 10 10 14  ; x, one",
+                   d.execute("continue"));
+        assert_eq!(4, d.computer().counter());
+    }
+
+    #[test]
+    fn it_deletes_breakpoints() {
+        let mut d = debugger();
+        d.execute("break loop+4");
+
+        assert_eq!("breakpoint at 00000008 <loop+4>", d.execute("break"));
+        assert_eq!("deleted breakpoint at 00000008 <loop+4>", d.execute("delete loop+4"));
+        assert_eq!("no breakpoints", d.execute("break"));
+    }
+
+    #[test]
+    fn it_stops_on_watchpoints_in_the_display() {
+        let mut d = debugger();
+        d.execute("step");
+
+        assert_eq!("watchpoint (w) on 00000018..00000028", d.execute("watch display"));
+        assert_eq!("watchpoint, write to 00000018 <display> by 00000008 <loop+4>: \
+                    00000000 -> 00000001\n=> 0000000c                          lpc e 4 0  ; loop",
+                   d.execute("continue"));
+    }
+
+    #[test]
+    fn it_steps_backwards() {
+        let mut d = debugger();
+        d.execute("step 6");
+
+        assert_eq!("=> 00000004 <loop>                   add 10 10 14  ; x, one",
+                   d.execute("reverse-step 2"));
+        assert_eq!("00000010 <x>: 00000018", d.execute("print x"));
+        assert_eq!("start of history\n\
+                    => 00000000                          cfv 18 4 4  ; display, loop",
+                   d.execute("rs 10"));
+    }
+
+    #[test]
+    fn it_finds_the_last_write_to_the_display() {
+        let mut d = debugger();
+        d.execute("step 8");
+        d.execute("watch display");
+
+        assert_eq!("watchpoint, write to 00000019 <display+1> by 00000008 <loop+4>: \
+                    00000000 -> 00000001\n=> 00000008                          sra 14 10 0  ; one, x",
+                   d.execute("reverse-continue"));
+        assert_eq!("00000010 <x>: 00000019", d.execute("print x"));
+    }
+
+    #[test]
+    fn it_prints_and_sets_memory() {
+        let mut d = debugger();
+
+        assert_eq!("00000014 <one>: 00000007", d.execute("set one 7"));
+        assert_eq!("00000014 <one>: 00000007 00000000 00000000 00000000\n\
+                    00000018 <display>: 00000000",
+                   d.execute("print one 5"));
+    }
+
+    #[test]
+    fn it_disassembles_around_the_program_counter() {
+        let mut d = debugger();
+        d.execute("step 2");
+
+        assert_eq!("   00000000                          cfv 18 4 4  ; display, loop
+   00000004 <loop>                   add 10 10 14  ; x, one
+=> 00000008                          sra 14 10 0  ; one, x
+   0000000c                          lpc e 4 0  ; loop
+   00000010 <x>                      18 0 0 0",
+                   d.execute("disassemble"));
+    }
+
+    #[test]
+    fn it_presses_keys() {
+        let mut d = debugger();
+        d.computer_mut().load_from_slice(&[15, 4, 0, 0]);
+
+        assert_eq!("waiting for input, use `key` to press a key\n\
+                    => 00000000                          key 4 0 0  ; loop",
+                   d.execute("step"));
+        assert_eq!("pressed key 61", d.execute("key 61"));
+        d.execute("step");
+        assert_eq!(0x61, d.computer().peek(4));
+    }
+
+    #[test]
+    fn it_saves_and_restores_snapshots() {
+        let file = env::temp_dir().join(format!("chifir-debugger-snapshots-{}.snapshot", process::id()));
+        let path = file.to_str().unwrap();
+        let mut d = debugger();
+        d.execute("step 2");
+
+        assert_eq!(format!("saved snapshot to {}", path), d.execute(&format!("save {}", path)));
+        d.execute("step 5");
+        assert_eq!(format!("restored snapshot from {}\n\
+                            => 00000008                          sra 14 10 0  ; one, x",
+                           path),
+                   d.execute(&format!("restore {}", path)));
+        assert_eq!("00000010 <x>: 00000018", d.execute("print x"));
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn it_reports_unknown_labels() {
+        let mut d = debugger();
+
+        assert_eq!("unknown label or address `nowhere`", d.execute("break nowhere"));
+        assert_eq!("unknown command `jump`, try `help`", d.execute("jump"));
+    }
+}
@@ -0,0 +1,662 @@
+//! Showing the frames a computer draws.
+//!
+//! Every `drw` instruction hands the display memory to a `Display` as a
+//! `Frame`. The `Sixel` display draws frames on a terminal that understands
+//! Sixel graphics and is what `Computer::output` binds. For running without
+//! a terminal, the `Headless` display keeps frames in memory and the
+//! `FrameFiles` display saves them as images. Other displays can encode
+//! frames differently or check them in tests.
+//!
+//! Between frames the computer keeps track of which bands of the display, the
+//! strips of six rows that Sixel graphics draw at once, were written to. A
+//! display can redraw just those by implementing `update`.
+//!
+//! The top byte of the width given to `cfv` picks how pixel words are
+//! turned into colors, as listed under `Mode`. Programs that leave it zero
+//! get the original monochrome display.
+//!
+//! ```
+//! use std::io;
+//! use std::sync::{Arc, Mutex};
+//! use chifir::computer::Computer;
+//! use chifir::display::{Display, Frame};
+//!
+//! struct Count(Arc<Mutex<usize>>);
+//!
+//! impl Display for Count {
+//!     fn draw(&mut self, _frame: &Frame) -> io::Result<()> {
+//!         *self.0.lock().unwrap() += 1;
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let count = Arc::new(Mutex::new(0));
+//!
+//! let mut computer = Computer::new().display(Box::new(Count(count.clone())));
+//! computer.load(vec![
+//!     0xe, 0x0, 0x0, 0x0,  // drw
+//!     0xe, 0x0, 0x0, 0x0,  // drw
+//!     0x0, 0x0, 0x0, 0x0,  // brk
+//! ]);
+//! computer.run();
+//!
+//! assert_eq!(2, *count.lock().unwrap());
+//! ```
+
+use termion;
+
+use super::netpbm::{self, Format};
+use super::sixel;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// How the words in display memory are turned into colors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Nonzero pixels are white and zero pixels are black.
+    Monochrome,
+    /// The low byte of a pixel is its brightness, from black at 0 to white
+    /// at 0xff.
+    Grayscale,
+    /// The low byte of a pixel is an index into the 256 color `palette`.
+    Palette,
+    /// Pixels are packed 0xRRGGBB colors.
+    Rgb,
+}
+
+impl Mode {
+    /// Finds the mode numbered `number`, the top byte of the `cfv` width.
+    ///
+    /// Numbers without a mode are monochrome, like programs written before
+    /// there were other modes.
+    pub fn from_number(number: u32) -> Mode {
+        match number {
+            1 => Mode::Grayscale,
+            2 => Mode::Palette,
+            3 => Mode::Rgb,
+            _ => Mode::Monochrome,
+        }
+    }
+
+    /// Returns the number of the mode, the inverse of `from_number`.
+    pub fn number(&self) -> u32 {
+        match *self {
+            Mode::Monochrome => 0,
+            Mode::Grayscale => 1,
+            Mode::Palette => 2,
+            Mode::Rgb => 3,
+        }
+    }
+}
+
+/// A copy of the display memory at the time of a `drw`.
+///
+/// Pixels are stored row by row, one word per pixel, and are interpreted
+/// according to `mode`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub mode: Mode,
+    pub pixels: Vec<u32>,
+}
+
+impl Frame {
+    /// Returns the color of the pixel at `index` as 0xRRGGBB, or black if
+    /// the frame has no such pixel.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::display::{Frame, Mode};
+    ///
+    /// let frame = Frame {
+    ///     width: 3,
+    ///     height: 1,
+    ///     mode: Mode::Grayscale,
+    ///     pixels: vec![0x0, 0x80, 0x1ff],
+    /// };
+    ///
+    /// assert_eq!(0x808080, frame.color(1));
+    /// assert_eq!(0xffffff, frame.color(2));
+    /// ```
+    pub fn color(&self, index: usize) -> u32 {
+        let pixel = match self.pixels.get(index) {
+            Some(pixel) => *pixel,
+            None => return 0,
+        };
+
+        match self.mode {
+            Mode::Monochrome => if pixel > 0 { 0xff_ffff } else { 0 },
+            Mode::Grayscale => (pixel & 0xff) * 0x01_0101,
+            Mode::Palette => palette(pixel as u8),
+            Mode::Rgb => pixel & 0xff_ffff,
+        }
+    }
+
+    /// Returns a copy of the frame with every pixel blown up into a `factor`
+    /// by `factor` block.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chifir::display::{Frame, Mode};
+    ///
+    /// let frame = Frame {
+    ///     width: 2,
+    ///     height: 1,
+    ///     mode: Mode::Monochrome,
+    ///     pixels: vec![0x1, 0x0],
+    /// };
+    ///
+    /// let scaled = frame.scale(2);
+    ///
+    /// assert_eq!((4, 2), (scaled.width, scaled.height));
+    /// assert_eq!(vec![0x1, 0x1, 0x0, 0x0, 0x1, 0x1, 0x0, 0x0], scaled.pixels);
+    /// ```
+    pub fn scale(&self, factor: u32) -> Frame {
+        let factor = factor.max(1);
+        let width = self.width.saturating_mul(factor);
+        let height = self.height.saturating_mul(factor);
+
+        let mut pixels = Vec::with_capacity(width as usize * height as usize);
+        for y in 0..height as usize {
+            let row = y / factor as usize * self.width as usize;
+            for x in 0..width as usize {
+                pixels.push(self.pixels.get(row + x / factor as usize).cloned().unwrap_or(0));
+            }
+        }
+
+        Frame {
+            width,
+            height,
+            mode: self.mode,
+            pixels,
+        }
+    }
+}
+
+/// Returns color `index` of the palette used by `Mode::Palette` as 0xRRGGBB.
+///
+/// This is the usual 256 color terminal palette: 16 basic colors, a 6 by 6
+/// by 6 color cube and 24 shades of gray.
+pub fn palette(index: u8) -> u32 {
+    const BASIC: [u32; 16] = [0x00_0000, 0x80_0000, 0x00_8000, 0x80_8000,
+                              0x00_0080, 0x80_0080, 0x00_8080, 0xc0_c0c0,
+                              0x80_8080, 0xff_0000, 0x00_ff00, 0xff_ff00,
+                              0x00_00ff, 0xff_00ff, 0x00_ffff, 0xff_ffff];
+    const LEVELS: [u32; 6] = [0x00, 0x5f, 0x87, 0xaf, 0xd7, 0xff];
+
+    match index {
+        0..=15 => BASIC[index as usize],
+        16..=231 => {
+            let cube = index as usize - 16;
+            LEVELS[cube / 36] << 16 | LEVELS[cube / 6 % 6] << 8 | LEVELS[cube % 6]
+        }
+        _ => (8 + 10 * (index as u32 - 232)) * 0x01_0101,
+    }
+}
+
+/// Something that shows frames.
+///
+/// A display that returns an error stops the computer with
+/// `State::DisplayFailed`, and isn't given any more frames.
+pub trait Display {
+    /// Shows `frame`. Called on the first `drw` and whenever the whole
+    /// display has to be redrawn.
+    fn draw(&mut self, frame: &Frame) -> io::Result<()>;
+
+    /// Shows `frame` when only the bands in `bands` changed since the last
+    /// frame. Bands are numbered from the top and listed in order.
+    ///
+    /// Called on every other `drw`. The default draws the whole frame.
+    fn update(&mut self, frame: &Frame, bands: &[u32]) -> io::Result<()> {
+        let _ = bands;
+        self.draw(frame)
+    }
+}
+
+/// Draws frames as Sixel graphics in the top left corner of a terminal.
+///
+/// Small displays can be scaled up, drawing every pixel as a square block of
+/// terminal pixels.
+///
+/// # Examples
+///
+/// ```
+/// use chifir::display::{Display, Frame, Mode, Sixel};
+///
+/// let mut output = Vec::new();
+/// Sixel::new(&mut output).fit(30, 40).draw(&Frame {
+///     width: 1,
+///     height: 1,
+///     mode: Mode::Monochrome,
+///     pixels: vec![0x1],
+/// }).unwrap();
+///
+/// // Scaled up 30 times, the pixel fills five bands
+/// assert_eq!(5, output.iter().filter(|byte| **byte == b'-').count());
+/// ```
+pub struct Sixel<W: Write> {
+    output: W,
+    scale: Scale,
+}
+
+// How much to scale frames up by.
+enum Scale {
+    Factor(u32),
+    // As much as fits in a width and height in pixels.
+    Fit(u32, u32),
+}
+
+impl<W: Write> Sixel<W> {
+    /// Create a new `Sixel` display writing to `output`.
+    pub fn new(output: W) -> Self {
+        Sixel {
+            output,
+            scale: Scale::Factor(1),
+        }
+    }
+
+    /// Draws every pixel as a `factor` by `factor` block.
+    pub fn scale(mut self, factor: u32) -> Self {
+        self.scale = Scale::Factor(factor.max(1));
+        self
+    }
+
+    /// Scales frames up as far as they fit in `width` by `height` terminal
+    /// pixels, but never below their own size.
+    pub fn fit(mut self, width: u32, height: u32) -> Self {
+        self.scale = Scale::Fit(width, height);
+        self
+    }
+
+    fn factor(&self, frame: &Frame) -> u32 {
+        match self.scale {
+            Scale::Factor(factor) => factor,
+            Scale::Fit(width, height) => {
+                let across = width.checked_div(frame.width).unwrap_or(1);
+                let down = height.checked_div(frame.height).unwrap_or(1);
+                across.min(down).max(1)
+            }
+        }
+    }
+}
+
+impl<W: Write> Display for Sixel<W> {
+    fn draw(&mut self, frame: &Frame) -> io::Result<()> {
+        let factor = self.factor(frame);
+        let bytes = if factor > 1 {
+            encode(&frame.scale(factor))
+        } else {
+            encode(frame)
+        };
+
+        self.output.write_all(&bytes)?;
+        self.output.flush()
+    }
+
+    fn update(&mut self, frame: &Frame, bands: &[u32]) -> io::Result<()> {
+        if bands.is_empty() {
+            return Ok(());
+        }
+
+        // Band b of the frame becomes bands b * factor up to (b + 1) *
+        // factor of the scaled frame.
+        let factor = self.factor(frame);
+        let bytes = if factor > 1 {
+            let scaled: Vec<u32> = bands.iter()
+                .flat_map(|band| band * factor..(band + 1) * factor)
+                .collect();
+            encode_bands(&frame.scale(factor), &scaled)
+        } else {
+            encode_bands(frame, bands)
+        };
+
+        self.output.write_all(&bytes)?;
+        self.output.flush()
+    }
+}
+
+/// Keeps the most recent frames in memory instead of showing them.
+///
+/// Clones share their frames, so keep a clone around to look at the frames
+/// drawn by a `Computer`. Only the last 16 frames are kept unless `keep`
+/// says otherwise, so long runs don't use more and more memory.
+///
+/// # Examples
+///
+/// ```
+/// use chifir::computer::Computer;
+/// use chifir::display::Headless;
+///
+/// let headless = Headless::new();
+///
+/// let mut computer = Computer::new().display(Box::new(headless.clone()));
+/// computer.load(vec![
+///     0x11, 0x8, 0x2, 0x1,  // cfv 8 2 1
+///     0xe, 0x0, 0x0, 0x0,   // drw
+///     0x0, 0x1,
+/// ]);
+/// computer.run_for(2);
+///
+/// assert_eq!(vec![0x0, 0x1], headless.frames()[0].pixels);
+/// ```
+#[derive(Clone)]
+pub struct Headless {
+    frames: Arc<Mutex<VecDeque<Frame>>>,
+    keep: usize,
+}
+
+impl Headless {
+    /// Create a new `Headless` display without any frames.
+    pub fn new() -> Self {
+        Headless {
+            frames: Arc::new(Mutex::new(VecDeque::new())),
+            keep: 16,
+        }
+    }
+
+    /// Keeps the last `frames` frames, dropping older ones as new frames are
+    /// drawn.
+    pub fn keep(mut self, frames: usize) -> Self {
+        self.keep = frames;
+        self
+    }
+
+    /// Returns a copy of the frames that are kept, oldest first.
+    pub fn frames(&self) -> Vec<Frame> {
+        self.frames.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Default for Headless {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for Headless {
+    fn draw(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut frames = self.frames.lock().unwrap();
+        frames.push_back(frame.clone());
+        while frames.len() > self.keep {
+            frames.pop_front();
+        }
+        Ok(())
+    }
+}
+
+/// Writes every frame into a directory as a numbered Netpbm image as soon as
+/// it's drawn, for running without a terminal.
+///
+/// Frames are numbered from one, padded so the files sort in order. The
+/// directory has to exist already.
+pub struct FrameFiles {
+    directory: PathBuf,
+    format: Format,
+    count: u64,
+}
+
+impl FrameFiles {
+    /// Create a new `FrameFiles` display writing images in `format` into
+    /// `directory`.
+    pub fn new<P: Into<PathBuf>>(directory: P, format: Format) -> Self {
+        FrameFiles {
+            directory: directory.into(),
+            format,
+            count: 0,
+        }
+    }
+}
+
+impl Display for FrameFiles {
+    fn draw(&mut self, frame: &Frame) -> io::Result<()> {
+        self.count += 1;
+        let path = self.directory.join(netpbm::file_name(self.count, self.format));
+
+        File::create(&path)
+            .and_then(|file| netpbm::write(file, frame, self.format))
+            .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))
+    }
+}
+
+/// Encodes `frame` as Sixel graphics, preceded by moving the cursor to the
+/// top left corner.
+///
+/// Monochrome frames use the terminal's own colors. Frames in other modes
+/// define a color register for every color they use.
+pub fn encode(frame: &Frame) -> Vec<u8> {
+    format!("{}{}{}{}",
+            termion::cursor::Goto(1, 1),
+            sixel::begin(),
+            encode_pixels(frame),
+            sixel::end())
+        .into_bytes()
+}
+
+/// Encodes only the bands of `frame` listed in `bands`, which must be in
+/// order, leaving the rest of a frame drawn earlier in place.
+///
+/// Each run of neighbouring bands is drawn as its own image, which starts
+/// with a graphics newline for every band above it.
+///
+/// # Examples
+///
+/// ```
+/// use chifir::display::{self, Frame, Mode};
+///
+/// let frame = Frame {
+///     width: 1,
+///     height: 18,
+///     mode: Mode::Monochrome,
+///     pixels: vec![1; 18],
+/// };
+///
+/// assert_eq!(b"\x1b[1;1H\x1bPq--~$-\x1b\\".to_vec(), display::encode_bands(&frame, &[2]));
+/// ```
+pub fn encode_bands(frame: &Frame, bands: &[u32]) -> Vec<u8> {
+    let width = frame.width as usize;
+    let count = (frame.height as usize).div_ceil(6);
+    let bands: Vec<usize> = bands.iter().map(|band| *band as usize).filter(|band| *band < count).collect();
+
+    let mut bytes = Vec::new();
+    let mut index = 0;
+
+    while index < bands.len() {
+        let first = bands[index];
+        let mut last = first;
+        while index + 1 < bands.len() && bands[index + 1] == last + 1 {
+            index += 1;
+            last += 1;
+        }
+        index += 1;
+
+        let top = first * 6;
+        let bottom = cmp::min((last + 1) * 6, frame.height as usize);
+        let start = cmp::min(top * width, frame.pixels.len());
+        let end = cmp::min(bottom * width, frame.pixels.len());
+        let part = Frame {
+            width: frame.width,
+            height: (bottom - top) as u32,
+            mode: frame.mode,
+            pixels: frame.pixels[start..end].to_vec(),
+        };
+
+        bytes.extend(format!("{}{}{}{}{}",
+                             termion::cursor::Goto(1, 1),
+                             sixel::begin(),
+                             "-".repeat(first),
+                             encode_pixels(&part),
+                             sixel::end())
+            .into_bytes());
+    }
+
+    bytes
+}
+
+fn encode_pixels(frame: &Frame) -> String {
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+
+    match frame.mode {
+        Mode::Monochrome => sixel::from(&frame.pixels, width, height, false),
+        _ => {
+            let colors: Vec<u32> = (0..frame.pixels.len()).map(|index| frame.color(index)).collect();
+            sixel::from_colors(&colors, width, height)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_bands, palette, Display, Frame, FrameFiles, Headless, Mode, Sixel};
+    use netpbm::Format;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn it_draws_sixel_frames_at_the_top_left() {
+        let mut output = Vec::new();
+        Sixel::new(&mut output).draw(&Frame {
+            width: 1,
+            height: 6,
+            mode: Mode::Monochrome,
+            pixels: vec![1, 1, 1, 0, 0, 0],
+        }).unwrap();
+
+        assert_eq!(b"\x1b[1;1H\x1bPqF$-\x1b\\".to_vec(), output);
+    }
+
+    #[test]
+    fn it_draws_color_frames_with_color_registers() {
+        let mut output = Vec::new();
+        Sixel::new(&mut output).draw(&Frame {
+            width: 1,
+            height: 6,
+            mode: Mode::Rgb,
+            pixels: vec![0xff0000, 0xff0000, 0xff0000, 0x0000ff, 0x0000ff, 0x0000ff],
+        }).unwrap();
+
+        assert_eq!(b"\x1b[1;1H\x1bPq#0;2;100;0;0#1;2;0;0;100#0F$#1w$-\x1b\\".to_vec(),
+                   output);
+    }
+
+    #[test]
+    fn it_updates_runs_of_neighbouring_bands_as_one_image() {
+        let frame = Frame {
+            width: 2,
+            height: 20,
+            mode: Mode::Monochrome,
+            pixels: vec![1; 40],
+        };
+
+        let mut output = Vec::new();
+        Sixel::new(&mut output).update(&frame, &[0, 2, 3, 9]).unwrap();
+
+        assert_eq!(b"\x1b[1;1H\x1bPq~~$-\x1b\\\x1b[1;1H\x1bPq--~~$-BB$-\x1b\\".to_vec(), output);
+        assert_eq!(output[15..], encode_bands(&frame, &[2, 3])[..]);
+
+        let mut output = Vec::new();
+        Sixel::new(&mut output).update(&frame, &[]).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn it_scales_frames_and_their_bands() {
+        let frame = Frame {
+            width: 1,
+            height: 12,
+            mode: Mode::Monochrome,
+            pixels: vec![0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+        };
+
+        let mut output = Vec::new();
+        Sixel::new(&mut output).scale(2).update(&frame, &[1]).unwrap();
+        assert_eq!(encode_bands(&frame.scale(2), &[2, 3]), output);
+        assert_eq!(b"\x1b[1;1H\x1bPq--BB$-??$-\x1b\\".to_vec(), output);
+
+        let mut scaled = Vec::new();
+        Sixel::new(&mut scaled).scale(3).draw(&frame).unwrap();
+        let mut fitted = Vec::new();
+        Sixel::new(&mut fitted).fit(5, 36).draw(&frame).unwrap();
+        assert_eq!(scaled, fitted);
+
+        let mut unscaled = Vec::new();
+        Sixel::new(&mut unscaled).fit(0, 0).draw(&frame).unwrap();
+        assert_eq!(b"\x1b[1;1H\x1bPq?$-@$-\x1b\\".to_vec(), unscaled);
+    }
+
+    #[test]
+    fn it_maps_pixels_to_colors_by_mode() {
+        let frame = |mode| {
+            Frame {
+                width: 2,
+                height: 1,
+                mode,
+                pixels: vec![0x12_3456, 0xc4],
+            }
+        };
+
+        assert_eq!(0xffffff, frame(Mode::Monochrome).color(0));
+        assert_eq!(0x565656, frame(Mode::Grayscale).color(0));
+        assert_eq!(0x5fffd7, frame(Mode::Palette).color(0));
+        assert_eq!(0x123456, frame(Mode::Rgb).color(0));
+        assert_eq!(0x0, frame(Mode::Rgb).color(2));
+
+        assert_eq!(0xc0c0c0, palette(7));
+        assert_eq!(0xff0000, palette(196));
+        assert_eq!(0xeeeeee, palette(255));
+        assert_eq!(Mode::Palette, Mode::from_number(Mode::Palette.number()));
+        assert_eq!(Mode::Monochrome, Mode::from_number(0x80));
+    }
+
+    #[test]
+    fn it_writes_frame_files_as_they_are_drawn() {
+        let directory = env::temp_dir().join(format!("chifir-display-frame-files-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let mut files = FrameFiles::new(&directory, Format::Pbm);
+        for pixel in 0..2 {
+            files.draw(&Frame {
+                width: 1,
+                height: 1,
+                mode: Mode::Monochrome,
+                pixels: vec![pixel],
+            }).unwrap();
+        }
+
+        assert_eq!(b"P4\n1 1\n\x80".to_vec(), fs::read(directory.join("000001.pbm")).unwrap());
+        assert_eq!(b"P4\n1 1\n\x00".to_vec(), fs::read(directory.join("000002.pbm")).unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+
+        let error = files.draw(&Frame {
+            width: 0,
+            height: 0,
+            mode: Mode::Monochrome,
+            pixels: vec![],
+        }).unwrap_err();
+        assert!(error.to_string().contains("000003.pbm"));
+    }
+
+    #[test]
+    fn it_keeps_only_the_latest_headless_frames() {
+        let headless = Headless::new().keep(2);
+        for pixel in 0..3 {
+            headless.clone().draw(&Frame {
+                width: 1,
+                height: 1,
+                mode: Mode::Monochrome,
+                pixels: vec![pixel],
+            }).unwrap();
+        }
+
+        let pixels: Vec<Vec<u32>> = headless.frames().into_iter().map(|frame| frame.pixels).collect();
+        assert_eq!(vec![vec![1], vec![2]], pixels);
+    }
+}
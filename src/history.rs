@@ -0,0 +1,162 @@
+//! Execution history for stepping the virtual computer backwards.
+//!
+//! Every executed instruction leaves behind an `Undo` record with just
+//! enough to reverse it: the program counter and state before it ran, the
+//! value its store replaced and the display configuration it changed.
+//!
+//! Undo records only go back to the most recent `Checkpoint`, a full copy of
+//! the machine taken every `interval` steps. Going back further restores an
+//! older checkpoint and replays forward from it. Only `limit` checkpoints
+//! are kept, which bounds how far back the history reaches.
+//!
+//! Replaying has to make the same choices the original run did, so the key
+//! read by every `key` instruction is logged by step.
+
+use super::computer::State;
+use super::display::Mode;
+use super::memory::Memory;
+use super::trace::Store;
+use std::collections::{BTreeMap, VecDeque};
+
+/// The display address, width, height and mode set by `cfv`.
+pub type DisplayConfig = (u32, u32, u32, Mode);
+
+/// A full copy of the machine before the instruction at `step` ran.
+pub struct Checkpoint {
+    pub step: u64,
+    pub memory: Memory,
+    pub counter: u32,
+    pub state: State,
+    pub frames: u64,
+    pub display: DisplayConfig,
+}
+
+/// Everything needed to reverse a single instruction.
+pub struct Undo {
+    pub counter: u32,
+    pub state: State,
+    pub len: u64,
+    pub frames: u64,
+    pub store: Option<Store>,
+    pub display: Option<DisplayConfig>,
+}
+
+pub struct History {
+    interval: u64,
+    limit: usize,
+    /// The number of instructions executed since the program was loaded.
+    pub step: u64,
+    pub checkpoints: VecDeque<Checkpoint>,
+    /// Undo records for the steps since the most recent checkpoint.
+    pub undo: Vec<Undo>,
+    pub keys: BTreeMap<u64, u8>,
+}
+
+impl History {
+    pub fn new(interval: u64, limit: usize) -> Self {
+        History {
+            interval: interval.max(1),
+            limit: limit.max(1),
+            step: 0,
+            checkpoints: VecDeque::new(),
+            undo: Vec::new(),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Returns an empty history with the same interval and limit.
+    pub fn reset(&self) -> Self {
+        History::new(self.interval, self.limit)
+    }
+
+    /// Returns `true` if a checkpoint should be taken before the next step.
+    pub fn needs_checkpoint(&self) -> bool {
+        self.step.is_multiple_of(self.interval) &&
+        self.checkpoints.back().map(|checkpoint| checkpoint.step) != Some(self.step)
+    }
+
+    /// Adds a checkpoint, dropping the oldest ones past the limit along with
+    /// the keys logged before them.
+    pub fn push(&mut self, checkpoint: Checkpoint) {
+        self.checkpoints.push_back(checkpoint);
+        self.undo.clear();
+
+        while self.checkpoints.len() > self.limit {
+            self.checkpoints.pop_front();
+        }
+
+        let oldest = self.checkpoints.front().map(|checkpoint| checkpoint.step).unwrap_or(0);
+        self.keys = self.keys.split_off(&oldest);
+    }
+
+    /// Forgets every checkpoint taken after `step` and returns the index of
+    /// the most recent one at or before it.
+    pub fn rewind(&mut self, step: u64) -> Option<usize> {
+        let index = self.checkpoints.iter().rposition(|checkpoint| checkpoint.step <= step)?;
+        self.checkpoints.truncate(index + 1);
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, History};
+    use computer::State;
+    use display::Mode;
+    use memory::Memory;
+
+    fn checkpoint(step: u64) -> Checkpoint {
+        Checkpoint {
+            step,
+            memory: Memory::new(),
+            counter: 0,
+            state: State::Running,
+            frames: 0,
+            display: (0, 0, 0, Mode::Monochrome),
+        }
+    }
+
+    #[test]
+    fn it_takes_checkpoints_every_interval() {
+        let mut history = History::new(10, 4);
+        assert!(history.needs_checkpoint());
+
+        history.push(checkpoint(0));
+        assert!(!history.needs_checkpoint());
+
+        history.step = 5;
+        assert!(!history.needs_checkpoint());
+
+        history.step = 10;
+        assert!(history.needs_checkpoint());
+    }
+
+    #[test]
+    fn it_drops_the_oldest_checkpoints_and_their_keys() {
+        let mut history = History::new(10, 2);
+        history.keys.insert(5, 0x61);
+        history.keys.insert(15, 0x62);
+
+        for step in 0..3 {
+            history.push(checkpoint(step * 10));
+        }
+
+        let steps: Vec<u64> = history.checkpoints.iter().map(|checkpoint| checkpoint.step).collect();
+        assert_eq!(vec![10, 20], steps);
+        assert_eq!(vec![15], history.keys.keys().cloned().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn it_rewinds_to_the_closest_earlier_checkpoint() {
+        let mut history = History::new(10, 4);
+        for step in 0..3 {
+            history.push(checkpoint(step * 10));
+        }
+
+        assert_eq!(Some(1), history.rewind(19));
+        assert_eq!(2, history.checkpoints.len());
+
+        history.checkpoints.pop_front();
+        assert_eq!(None, history.rewind(9));
+    }
+}
@@ -6,5 +6,16 @@
 extern crate termion;
 
 pub mod computer;
+pub mod display;
+mod history;
+mod memory;
+pub mod netpbm;
+pub mod object;
+pub mod script;
 mod sixel;
+pub mod snapshot;
 pub mod compiler;
+pub mod debugger;
+pub mod linker;
+pub mod symbols;
+pub mod trace;
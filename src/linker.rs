@@ -0,0 +1,320 @@
+//! Linking relocatable objects into a single program.
+//!
+//! The linker lays objects out one after another in the order they were
+//! added, starting at address 0. Programs start running at address 0, so the
+//! first object should start with the program's entry point. Addresses in
+//! `.align` and `.org` count from the start of each object, and an object
+//! that uses `.align` is moved up to its alignment with zeroes in between.
+//!
+//! Every symbol an object imports has to be exported by exactly one object.
+//! The linked program is an object too, with the exports of every object and
+//! no imports, so it can be loaded at address 0 or linked again.
+//!
+//! ```
+//! use std::io::Write;
+//! use chifir::compiler::Compiler;
+//! use chifir::computer::Computer;
+//! use chifir::linker::Linker;
+//!
+//! let mut main = Compiler::new().relocatable();
+//! write!(main, "{}", "
+//! .extern double
+//!   call double
+//!   brk
+//! ").unwrap();
+//! main.compile().unwrap();
+//!
+//! let mut library = Compiler::new().relocatable();
+//! write!(library, "{}", "
+//! .global double
+//! .global x
+//! double:
+//!   nop
+//!   add x x x
+//!   ret double
+//! x:
+//!   .word 15
+//! ").unwrap();
+//! library.compile().unwrap();
+//!
+//! let program = Linker::new()
+//!     .object("main", main.object().unwrap().clone())
+//!     .object("library", library.object().unwrap().clone())
+//!     .link()
+//!     .unwrap();
+//!
+//! let mut computer = Computer::new();
+//! computer.load_from_slice(&program.code);
+//! computer.run();
+//!
+//! assert_eq!(0x2a, computer.peek(program.exports["x"]));
+//! ```
+
+use super::object::{self, Object, Relocation};
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+
+pub struct Linker {
+    objects: Vec<(String, Object)>,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Linker { objects: Vec::new() }
+    }
+
+    /// Adds an object to the end of the program. `name` is used to report
+    /// problems with it, and is usually the object's file name.
+    pub fn object<S: Into<String>>(mut self, name: S, object: Object) -> Self {
+        self.objects.push((name.into(), object));
+        self
+    }
+
+    /// Places every object and resolves their symbols, reporting every
+    /// symbol that's undefined or defined more than once, and every
+    /// relocation outside its object's code.
+    pub fn link(&self) -> Result<Object, Vec<LinkError>> {
+        let mut errors = Vec::new();
+        let mut program = Object::default();
+
+        // Exported symbols, along with the object defining them.
+        let mut symbols: BTreeMap<&str, (u32, &str)> = BTreeMap::new();
+        let mut bases = Vec::new();
+
+        for (name, object) in &self.objects {
+            let align = object.align.max(1);
+            match object::lcm(program.align, align) {
+                Some(align) => program.align = align,
+                None => {
+                    errors.push(LinkError::Align {
+                        align,
+                        object: name.clone(),
+                    })
+                }
+            }
+
+            let padding = (align - program.code.len() as u32 % align) % align;
+            program.code.resize(program.code.len() + padding as usize, 0);
+            let base = program.code.len() as u32;
+            bases.push(base);
+            program.code.extend_from_slice(&object.code);
+
+            for (symbol, address) in &object.exports {
+                match symbols.get(symbol.as_str()) {
+                    Some(&(_, first)) => {
+                        errors.push(LinkError::Duplicate {
+                            symbol: symbol.clone(),
+                            first: first.to_string(),
+                            second: name.clone(),
+                        })
+                    }
+                    None => {
+                        symbols.insert(symbol, (base.wrapping_add(*address), name));
+                    }
+                }
+            }
+        }
+
+        for ((name, object), &base) in self.objects.iter().zip(bases.iter()) {
+            for import in &object.imports {
+                if !symbols.contains_key(import.as_str()) {
+                    errors.push(LinkError::Undefined {
+                        symbol: import.clone(),
+                        object: name.clone(),
+                    });
+                }
+            }
+
+            // Every word that gets relocated now holds an address inside the
+            // program, so it still moves along with the program.
+            for relocation in &object.relocations {
+                let offset = match relocation.symbol {
+                    Some(ref symbol) => {
+                        match symbols.get(symbol.as_str()) {
+                            Some(&(address, _)) => address,
+                            None => continue,
+                        }
+                    }
+                    None => base,
+                };
+
+                if relocation.address as usize >= object.code.len() {
+                    errors.push(LinkError::Relocation {
+                        address: relocation.address,
+                        object: name.clone(),
+                    });
+                    continue;
+                }
+
+                let address = base.wrapping_add(relocation.address);
+                let word = &mut program.code[address as usize];
+                *word = word.wrapping_add(offset);
+                program.relocations.push(Relocation {
+                    address,
+                    symbol: None,
+                });
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        program.exports = symbols.into_iter()
+            .map(|(symbol, (address, _))| (symbol.to_string(), address))
+            .collect();
+        Ok(program)
+    }
+}
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A problem that keeps objects from being linked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkError {
+    /// An object imports a symbol no object exports.
+    Undefined { symbol: String, object: String },
+    /// Two objects export the same symbol.
+    Duplicate {
+        symbol: String,
+        first: String,
+        second: String,
+    },
+    /// An object has a relocation past the end of its code.
+    Relocation { address: u32, object: String },
+    /// An object's alignment and the ones before it add up to more than
+    /// the address space.
+    Align { align: u32, object: String },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LinkError::Undefined { ref symbol, ref object } => {
+                write!(f, "{}: undefined symbol `{}`", object, symbol)
+            }
+            LinkError::Duplicate { ref symbol, ref first, ref second } => {
+                write!(f, "{}: `{}` is already defined in {}", second, symbol, first)
+            }
+            LinkError::Relocation { address, ref object } => {
+                write!(f, "{}: relocation at {:x} is outside the code", object, address)
+            }
+            LinkError::Align { align, ref object } => {
+                write!(f, "{}: can't align to {:x} words along with the objects before it", object, align)
+            }
+        }
+    }
+}
+
+impl error::Error for LinkError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkError, Linker};
+    use object::{Object, Relocation};
+
+    fn object(code: Vec<u32>, exports: &[(&str, u32)], relocations: &[(u32, Option<&str>)]) -> Object {
+        let mut imports: Vec<String> = relocations.iter()
+            .filter_map(|&(_, symbol)| symbol.map(|symbol| symbol.to_string()))
+            .collect();
+        imports.dedup();
+
+        Object {
+            code,
+            exports: exports.iter().map(|&(name, address)| (name.to_string(), address)).collect(),
+            imports,
+            relocations: relocations.iter()
+                .map(|&(address, symbol)| {
+                    Relocation {
+                        address,
+                        symbol: symbol.map(|symbol| symbol.to_string()),
+                    }
+                })
+                .collect(),
+            ..Object::default()
+        }
+    }
+
+    #[test]
+    fn it_places_objects_in_order_and_resolves_their_symbols() {
+        let main = object(vec![0x1, 0x2, 0x0, 0x0, 0x1], &[], &[(0x1, None), (0x2, Some("f")), (0x4, Some("f"))]);
+        let library = object(vec![0x0, 0x7, 0x3], &[("f", 0x1)], &[(0x2, None)]);
+
+        let program = Linker::new().object("main", main).object("library", library).link().unwrap();
+
+        assert_eq!(vec![0x1, 0x2, 0x6, 0x0, 0x7, 0x0, 0x7, 0x8], program.code);
+        assert_eq!(Some(&0x6), program.exports.get("f"));
+        assert!(program.imports.is_empty());
+
+        let relocated: Vec<u32> = program.relocations.iter().map(|relocation| relocation.address).collect();
+        assert_eq!(vec![0x1, 0x2, 0x4, 0x7], relocated);
+        assert!(program.relocations.iter().all(|relocation| relocation.symbol.is_none()));
+    }
+
+    #[test]
+    fn it_reports_undefined_and_duplicate_symbols() {
+        let main = object(vec![0x0, 0x0], &[("start", 0x0)], &[(0x1, Some("print"))]);
+        let other = object(vec![0x0], &[("start", 0x0)], &[]);
+
+        let errors = Linker::new().object("main.o", main).object("other.o", other).link().unwrap_err();
+
+        assert_eq!(vec![LinkError::Duplicate {
+                            symbol: "start".to_string(),
+                            first: "main.o".to_string(),
+                            second: "other.o".to_string(),
+                        },
+                        LinkError::Undefined {
+                            symbol: "print".to_string(),
+                            object: "main.o".to_string(),
+                        }],
+                   errors);
+
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(vec!["other.o: `start` is already defined in main.o", "main.o: undefined symbol `print`"],
+                   messages);
+    }
+
+    #[test]
+    fn it_reports_relocations_outside_the_code() {
+        let main = object(vec![0x0, 0x0], &[], &[(0x1, None), (0x2, None)]);
+
+        let errors = Linker::new().object("main.o", main).link().unwrap_err();
+
+        assert_eq!(vec![LinkError::Relocation {
+                            address: 0x2,
+                            object: "main.o".to_string(),
+                        }],
+                   errors);
+        assert_eq!("main.o: relocation at 2 is outside the code", errors[0].to_string());
+    }
+    #[test]
+    fn it_aligns_each_object_to_its_alignment() {
+        let main = object(vec![0x1, 0x2, 0x3], &[], &[(0x0, Some("table"))]);
+        let mut table = object(vec![0x7, 0x8], &[("table", 0x0)], &[(0x1, None)]);
+        table.align = 0x4;
+
+        let program = Linker::new().object("main", main).object("table", table).link().unwrap();
+
+        assert_eq!(vec![0x5, 0x2, 0x3, 0x0, 0x7, 0xc], program.code);
+        assert_eq!(Some(&0x4), program.exports.get("table"));
+        assert_eq!(0x4, program.align);
+    }
+
+    #[test]
+    fn it_reports_alignments_that_dont_fit_together() {
+        let mut first = object(vec![0x0], &[], &[]);
+        first.align = 0x1_0000;
+        let mut second = object(vec![0x0], &[], &[]);
+        second.align = 0x1_0001;
+
+        let errors = Linker::new().object("first.o", first).object("second.o", second).link().unwrap_err();
+
+        assert_eq!(vec!["second.o: can't align to 10001 words along with the objects before it"],
+                   errors.iter().map(|error| error.to_string()).collect::<Vec<String>>());
+    }
+}
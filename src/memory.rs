@@ -0,0 +1,228 @@
+//! Sparse, paged memory for the virtual computer.
+//!
+//! The first 2<sup>20</sup> words are low memory, kept in a single vector
+//! that grows up to the highest address read or written, like the memory of
+//! a small program always has. Everything above it is split into pages of
+//! 4096 words. A page is only allocated the first time a word inside it is
+//! written to. Reading from a page that was never written returns zero. This
+//! lets programs address the full 32 bit address space without allocating
+//! gigabytes of zeroes.
+
+use std::collections::HashMap;
+
+const PAGE_BITS: u32 = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const PAGE_MASK: u32 = (PAGE_SIZE as u32) - 1;
+
+/// The number of words in low memory.
+pub const LOW_SIZE: u32 = 1 << 20;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Memory {
+    low: Vec<u32>,
+    pages: HashMap<u32, Box<[u32; PAGE_SIZE]>>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory {
+            low: Vec::new(),
+            pages: HashMap::new(),
+        }
+    }
+
+    /// Returns one past the highest address in low memory that has been read
+    /// or written.
+    pub fn len(&self) -> u64 {
+        self.low.len() as u64
+    }
+
+    /// Sets the accessed length of low memory, for undoing accesses.
+    pub fn set_len(&mut self, len: u64) {
+        self.low.resize(len.min(LOW_SIZE as u64) as usize, 0);
+    }
+
+    /// Returns the number of pages that have been allocated above low
+    /// memory.
+    #[cfg(test)]
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Returns the value at `address` without marking it as accessed.
+    pub fn peek(&self, address: u32) -> u32 {
+        if address < LOW_SIZE {
+            return self.low.get(address as usize).cloned().unwrap_or(0);
+        }
+
+        match self.pages.get(&(address >> PAGE_BITS)) {
+            Some(page) => page[(address & PAGE_MASK) as usize],
+            None => 0,
+        }
+    }
+
+    /// Returns the value at `address` and marks it as accessed.
+    pub fn read(&mut self, address: u32) -> u32 {
+        self.touch(address);
+        self.peek(address)
+    }
+
+    /// Stores `value` at `address`, allocating its page if needed.
+    pub fn write(&mut self, address: u32, value: u32) {
+        if address < LOW_SIZE {
+            self.touch(address);
+            self.low[address as usize] = value;
+            return;
+        }
+
+        let page = self.pages
+            .entry(address >> PAGE_BITS)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE]));
+        page[(address & PAGE_MASK) as usize] = value;
+    }
+
+    /// Copies `len` words starting at `address` into a vector.
+    pub fn slice(&self, address: u32, len: usize) -> Vec<u32> {
+        (0..len).map(|offset| self.peek(address.wrapping_add(offset as u32))).collect()
+    }
+
+    /// Frees every page and resets the accessed length to zero.
+    pub fn clear(&mut self) {
+        self.low.clear();
+        self.pages.clear();
+    }
+
+    /// Returns low memory from zero up to `len()`.
+    pub fn as_slice(&self) -> &[u32] {
+        self.low.as_slice()
+    }
+
+    /// Copies every page holding low memory or allocated above it, along
+    /// with its page number, in address order.
+    pub fn allocated_pages(&self) -> Vec<(u32, Box<[u32; PAGE_SIZE]>)> {
+        let mut pages: Vec<(u32, Box<[u32; PAGE_SIZE]>)> = self.low
+            .chunks(PAGE_SIZE)
+            .enumerate()
+            .map(|(number, words)| {
+                let mut page = Box::new([0; PAGE_SIZE]);
+                page[..words.len()].copy_from_slice(words);
+                (number as u32, page)
+            })
+            .collect();
+
+        let mut high: Vec<(u32, Box<[u32; PAGE_SIZE]>)> = self.pages
+            .iter()
+            .map(|(number, page)| (*number, page.clone()))
+            .collect();
+        high.sort_by_key(|&(number, _)| number);
+
+        pages.extend(high);
+        pages
+    }
+
+    /// Replaces the page numbered `number`. Pages in low memory extend the
+    /// accessed length to their end, so `set_len` should be called
+    /// afterwards.
+    pub fn insert_page(&mut self, number: u32, page: Box<[u32; PAGE_SIZE]>) {
+        let start = (number as usize) << PAGE_BITS;
+        if start < LOW_SIZE as usize {
+            if self.low.len() < start + PAGE_SIZE {
+                self.low.resize(start + PAGE_SIZE, 0);
+            }
+            self.low[start..start + PAGE_SIZE].copy_from_slice(&page[..]);
+        } else {
+            self.pages.insert(number, page);
+        }
+    }
+
+    // Grows low memory to cover `address`. Addresses above low memory are
+    // never counted as accessed.
+    fn touch(&mut self, address: u32) {
+        if address < LOW_SIZE && address as usize >= self.low.len() {
+            self.low.resize(address as usize + 1, 0);
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Memory, LOW_SIZE, PAGE_SIZE};
+
+    #[test]
+    fn it_reads_zero_from_untouched_pages() {
+        let m = Memory::new();
+
+        assert_eq!(0, m.peek(0xFFFF_FFF0));
+        assert_eq!(0, m.pages());
+    }
+
+    #[test]
+    fn it_does_not_allocate_pages_when_reading() {
+        let mut m = Memory::new();
+
+        assert_eq!(0, m.read(0xFFFF_FFF0));
+        assert_eq!(0, m.pages());
+        assert_eq!(0, m.len());
+    }
+
+    #[test]
+    fn it_allocates_one_page_per_write_above_low_memory() {
+        let mut m = Memory::new();
+        m.write(0xFFFF_FFF0, 7);
+        m.write(0xFFFF_FFFF, 8);
+        m.write(LOW_SIZE, 9);
+        m.write(1, 10);
+
+        assert_eq!(2, m.pages());
+        assert_eq!(7, m.peek(0xFFFF_FFF0));
+        assert_eq!(8, m.peek(0xFFFF_FFFF));
+        assert_eq!(9, m.peek(LOW_SIZE));
+        assert_eq!(10, m.peek(1));
+        assert_eq!(2, m.len());
+    }
+
+    #[test]
+    fn it_slices_across_page_boundaries() {
+        let mut m = Memory::new();
+        m.write(LOW_SIZE - 1, 1);
+        m.write(LOW_SIZE, 2);
+
+        assert_eq!(vec![0, 1, 2, 0], m.slice(LOW_SIZE - 2, 4));
+    }
+
+    #[test]
+    fn it_borrows_low_memory_up_to_the_last_access() {
+        let mut m = Memory::new();
+        m.write(0, 1);
+        m.write(1, 2);
+        m.write(4, 3);
+        m.read(6);
+        m.peek(8);
+
+        assert_eq!(&[1, 2, 0, 0, 3, 0, 0], m.as_slice());
+    }
+
+    #[test]
+    fn it_copies_low_memory_into_pages() {
+        let mut m = Memory::new();
+        m.write(PAGE_SIZE as u32, 1);
+        m.write(0xFFFF_FFF0, 2);
+
+        let pages = m.allocated_pages();
+        let numbers: Vec<u32> = pages.iter().map(|&(number, _)| number).collect();
+        assert_eq!(vec![0, 1, 0xFFFFF], numbers);
+
+        let mut copy = Memory::new();
+        for (number, page) in pages {
+            copy.insert_page(number, page);
+        }
+        copy.set_len(m.len());
+        assert_eq!(m, copy);
+    }
+}
@@ -0,0 +1,197 @@
+//! Writing frames as Netpbm images.
+//!
+//! Netpbm images are simple enough to check byte by byte in tests and are
+//! understood by most image viewers and converters. Frames can be written as
+//! PBM bitmaps, PGM grayscale or PPM color images, all in their binary forms.
+//!
+//! Pixels get the color the frame's `Mode` gives them. Grayscale images use
+//! the brightness of that color, and bitmaps only show whether it's black.
+//! In a monochrome frame a pixel is on when its word is nonzero. On pixels
+//! are white and off pixels are black, like on a screen.
+//!
+//! ```
+//! use chifir::display::{Frame, Mode};
+//! use chifir::netpbm::{self, Format};
+//!
+//! let frame = Frame {
+//!     width: 2,
+//!     height: 1,
+//!     mode: Mode::Monochrome,
+//!     pixels: vec![0, 1],
+//! };
+//!
+//! let mut image = Vec::new();
+//! netpbm::write(&mut image, &frame, Format::Pgm).unwrap();
+//!
+//! assert_eq!(b"P5\n2 1\n255\n\x00\xff".to_vec(), image);
+//! ```
+
+use super::display::Frame;
+use std::io::{self, Write};
+
+/// The kinds of Netpbm image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A bitmap with one bit per pixel.
+    Pbm,
+    /// A grayscale image with one byte per pixel.
+    Pgm,
+    /// A color image with three bytes per pixel.
+    Ppm,
+}
+
+impl Format {
+    /// Returns the usual file extension for the format, without a dot.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Pbm => "pbm",
+            Format::Pgm => "pgm",
+            Format::Ppm => "ppm",
+        }
+    }
+
+    /// Finds the format with the file extension `extension`.
+    pub fn from_extension(extension: &str) -> Option<Format> {
+        match extension {
+            "pbm" => Some(Format::Pbm),
+            "pgm" => Some(Format::Pgm),
+            "ppm" => Some(Format::Ppm),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the file name for the frame numbered `number`, counting from one
+/// like `Computer::frames`.
+///
+/// Numbers are padded so the files sort in order.
+pub fn file_name(number: u64, format: Format) -> String {
+    format!("{:06}.{}", number, format.extension())
+}
+
+/// Writes `frame` to `writer` as an image in `format`.
+pub fn write<W: Write>(mut writer: W, frame: &Frame, format: Format) -> io::Result<()> {
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    let color = |x: usize, y: usize| frame.color(y * width + x);
+
+    let mut bytes = Vec::new();
+
+    match format {
+        Format::Pbm => {
+            write!(bytes, "P4\n{} {}\n", width, height)?;
+
+            // Rows are packed eight pixels to a byte, with 1 for black.
+            for y in 0..height {
+                for x in (0..width).step_by(8) {
+                    let mut byte = 0;
+                    for bit in 0..8 {
+                        if x + bit < width && color(x + bit, y) == 0 {
+                            byte |= 0x80 >> bit;
+                        }
+                    }
+                    bytes.push(byte);
+                }
+            }
+        }
+        Format::Pgm => {
+            write!(bytes, "P5\n{} {}\n255\n", width, height)?;
+
+            for y in 0..height {
+                for x in 0..width {
+                    bytes.push(brightness(color(x, y)));
+                }
+            }
+        }
+        Format::Ppm => {
+            write!(bytes, "P6\n{} {}\n255\n", width, height)?;
+
+            for y in 0..height {
+                for x in 0..width {
+                    let color = color(x, y);
+                    bytes.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8]);
+                }
+            }
+        }
+    }
+
+    writer.write_all(&bytes)
+}
+
+// Returns the luma of a 0xRRGGBB color, weighting the channels like ITU-R
+// BT.601 so white stays 255.
+fn brightness(color: u32) -> u8 {
+    let red = color >> 16 & 0xff;
+    let green = color >> 8 & 0xff;
+    let blue = color & 0xff;
+
+    ((red * 299 + green * 587 + blue * 114 + 500) / 1000) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_name, write, Format};
+    use display::{Frame, Mode};
+
+    fn frame() -> Frame {
+        Frame {
+            width: 10,
+            height: 2,
+            mode: Mode::Monochrome,
+            pixels: vec![1, 0, 1, 0, 1, 0, 1, 0, 1, 0,
+                         0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        }
+    }
+
+    fn image(format: Format) -> Vec<u8> {
+        let mut image = Vec::new();
+        write(&mut image, &frame(), format).unwrap();
+        image
+    }
+
+    #[test]
+    fn it_writes_bitmaps_with_padded_rows() {
+        assert_eq!(b"P4\n10 2\n\x55\x40\xff\x80".to_vec(), image(Format::Pbm));
+    }
+
+    #[test]
+    fn it_writes_grayscale_and_color_images() {
+        let pgm = image(Format::Pgm);
+        assert_eq!(b"P5\n10 2\n255\n\xff\x00\xff".to_vec(), pgm[..15].to_vec());
+        assert_eq!(12 + 20, pgm.len());
+
+        let ppm = image(Format::Ppm);
+        assert_eq!(b"P6\n10 2\n255\n\xff\xff\xff\x00".to_vec(), ppm[..16].to_vec());
+        assert_eq!(12 + 60, ppm.len());
+    }
+
+    #[test]
+    fn it_writes_the_colors_of_the_frame_mode() {
+        let frame = Frame {
+            width: 3,
+            height: 1,
+            mode: Mode::Rgb,
+            pixels: vec![0xff0000, 0x000000, 0x204060],
+        };
+
+        let mut ppm = Vec::new();
+        write(&mut ppm, &frame, Format::Ppm).unwrap();
+        assert_eq!(b"P6\n3 1\n255\n\xff\x00\x00\x00\x00\x00\x20\x40\x60".to_vec(), ppm);
+
+        let mut pgm = Vec::new();
+        write(&mut pgm, &frame, Format::Pgm).unwrap();
+        assert_eq!(b"P5\n3 1\n255\n\x4c\x00\x3a".to_vec(), pgm);
+
+        let mut pbm = Vec::new();
+        write(&mut pbm, &frame, Format::Pbm).unwrap();
+        assert_eq!(b"P4\n3 1\n\x40".to_vec(), pbm);
+    }
+
+    #[test]
+    fn it_finds_formats_by_extension() {
+        assert_eq!(Some(Format::Ppm), Format::from_extension("ppm"));
+        assert_eq!(None, Format::from_extension("png"));
+        assert_eq!("pbm", Format::Pbm.extension());
+        assert_eq!("000012.pgm", file_name(12, Format::Pgm));
+    }
+}
@@ -0,0 +1,334 @@
+//! Relocatable object files.
+//!
+//! An object is a program compiled as if it started at address 0, along with
+//! what a linker needs to move it somewhere else and connect it to other
+//! objects: the labels it exports, the symbols it imports and a relocation
+//! for every word that holds an address.
+//!
+//! A relocation says what to add to a word when the object is placed. Words
+//! holding an address inside the object get the address the object starts
+//! at, and words referring to an imported symbol get the address of that
+//! symbol. An object without imports can be loaded at address 0 as it is.
+//!
+//! An object that uses `.align` has to start at an address its alignments
+//! divide, so its aligned words stay aligned wherever it's placed.
+//!
+//! ```
+//! use std::io::{Cursor, Write};
+//! use chifir::compiler::Compiler;
+//! use chifir::object::{Object, Relocation};
+//!
+//! let mut compiler = Compiler::new().relocatable();
+//! write!(compiler, "{}", "
+//! .global start
+//! .extern print
+//! start:
+//!   call print
+//!   brk
+//! ").unwrap();
+//! compiler.compile().unwrap();
+//!
+//! let object = compiler.object().unwrap();
+//! assert_eq!(Some(&0x0), object.exports.get("start"));
+//! assert_eq!(vec!["print".to_string()], object.imports);
+//! assert!(object.relocations.contains(&Relocation { address: 0xe, symbol: Some("print".to_string()) }));
+//!
+//! let mut file = Vec::new();
+//! object.write_to(&mut file).unwrap();
+//! assert_eq!(object, &Object::read_from(Cursor::new(file)).unwrap());
+//! ```
+//!
+//! # Format
+//!
+//! Objects start with the four bytes `CHOB` and a version byte, currently 2.
+//! Numbers are 4 byte little endian and the fields follow one after another.
+//! Names are a 4 byte length followed by that many bytes of UTF-8. Version 1
+//! objects had no alignment and can't be read anymore.
+//!
+//! |Field              |Size                                          |
+//! |:------------------|:---------------------------------------------|
+//! |Alignment          |4 bytes                                       |
+//! |Code length        |4 bytes                                       |
+//! |Code               |One word per bytecode                         |
+//! |Export count       |4 bytes                                       |
+//! |Exports            |Name then address, for each export            |
+//! |Import count       |4 bytes                                       |
+//! |Imports            |Name, for each import                         |
+//! |Relocation count   |4 bytes                                       |
+//! |Relocations        |Address then symbol, for each relocation      |
+//!
+//! A relocation's symbol is 0 for the address the object is placed at, or
+//! one more than the index of an import.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"CHOB";
+const VERSION: u8 = 2;
+
+/// A compiled program that can still be moved and linked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Object {
+    /// The number of words the address the object is placed at has to be a
+    /// multiple of, 1 unless it uses `.align`.
+    pub align: u32,
+    /// The bytecodes, as if the object was loaded at address 0.
+    pub code: Vec<u32>,
+    /// The address of every exported label, counting from the start of the
+    /// object.
+    pub exports: BTreeMap<String, u32>,
+    /// The symbols other objects have to define, in order.
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A word that has to be adjusted when an object is placed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    /// Where the word is, counting from the start of the object.
+    pub address: u32,
+    /// The imported symbol whose address is added to the word, or `None` to
+    /// add the address the object is placed at.
+    pub symbol: Option<String>,
+}
+
+impl Default for Object {
+    fn default() -> Self {
+        Object {
+            align: 1,
+            code: Vec::new(),
+            exports: BTreeMap::new(),
+            imports: Vec::new(),
+            relocations: Vec::new(),
+        }
+    }
+}
+
+/// Returns `true` if `bytes` start like an object file.
+pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Returns the smallest alignment that's a multiple of both `a` and `b`, or
+// `None` if it doesn't fit in an address.
+pub(crate) fn lcm(a: u32, b: u32) -> Option<u32> {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        let remainder = x % y;
+        x = y;
+        y = remainder;
+    }
+    (a / x).checked_mul(b)
+}
+
+impl Object {
+    /// Writes the object to `writer`.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        put_u32(&mut bytes, self.align);
+
+        put_u32(&mut bytes, self.code.len() as u32);
+        for word in &self.code {
+            put_u32(&mut bytes, *word);
+        }
+
+        put_u32(&mut bytes, self.exports.len() as u32);
+        for (name, address) in &self.exports {
+            put_name(&mut bytes, name);
+            put_u32(&mut bytes, *address);
+        }
+
+        put_u32(&mut bytes, self.imports.len() as u32);
+        for name in &self.imports {
+            put_name(&mut bytes, name);
+        }
+
+        put_u32(&mut bytes, self.relocations.len() as u32);
+        for relocation in &self.relocations {
+            let symbol = match relocation.symbol {
+                Some(ref symbol) => {
+                    match self.imports.iter().position(|import| import == symbol) {
+                        Some(index) => index as u32 + 1,
+                        None => return Err(invalid(format!("`{}` isn't imported", symbol))),
+                    }
+                }
+                None => 0,
+            };
+            put_u32(&mut bytes, relocation.address);
+            put_u32(&mut bytes, symbol);
+        }
+
+        writer.write_all(&bytes)?;
+        writer.flush()
+    }
+
+    /// Reads an object from `reader`, checking the header and version.
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+
+        if !is_object(&header) {
+            return Err(invalid("not a Chifir object".to_string()));
+        }
+        if header[4] != VERSION {
+            return Err(invalid(format!("unsupported object version {}", header[4])));
+        }
+
+        let align = get_u32(&mut reader)?;
+        if align == 0 {
+            return Err(invalid("can't align to 0 words".to_string()));
+        }
+
+        // Everything is read one item at a time, so a corrupt count fails on
+        // the end of the file rather than on allocating memory.
+        let mut code = Vec::new();
+        for _ in 0..get_u32(&mut reader)? {
+            code.push(get_u32(&mut reader)?);
+        }
+
+        let mut exports = BTreeMap::new();
+        for _ in 0..get_u32(&mut reader)? {
+            let name = get_name(&mut reader)?;
+            exports.insert(name, get_u32(&mut reader)?);
+        }
+
+        let mut imports = Vec::new();
+        for _ in 0..get_u32(&mut reader)? {
+            imports.push(get_name(&mut reader)?);
+        }
+
+        let mut relocations = Vec::new();
+        for _ in 0..get_u32(&mut reader)? {
+            let address = get_u32(&mut reader)?;
+            if address as usize >= code.len() {
+                return Err(invalid(format!("relocation at {:x} is outside the code", address)));
+            }
+
+            let symbol = match get_u32(&mut reader)? {
+                0 => None,
+                index => {
+                    match imports.get(index as usize - 1) {
+                        Some(name) => Some(name.clone()),
+                        None => return Err(invalid(format!("unknown import {}", index))),
+                    }
+                }
+            };
+            relocations.push(Relocation { address, symbol });
+        }
+
+        Ok(Object {
+            align,
+            code,
+            exports,
+            imports,
+            relocations,
+        })
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_name(bytes: &mut Vec<u8>, name: &str) {
+    put_u32(bytes, name.len() as u32);
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+fn get_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn get_name<R: Read>(reader: &mut R) -> io::Result<String> {
+    let length = get_u32(reader)?;
+
+    let mut bytes = Vec::new();
+    reader.by_ref().take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length as usize {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "object is truncated"));
+    }
+
+    String::from_utf8(bytes).map_err(|error| invalid(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_object, lcm, Object, Relocation};
+    use std::io::Cursor;
+
+    fn object() -> Object {
+        let mut object = Object {
+            align: 0x4,
+            code: vec![0x1, 0x2, 0x0, 0x0, 0x5],
+            imports: vec!["print".to_string()],
+            relocations: vec![Relocation {
+                                  address: 0x1,
+                                  symbol: None,
+                              },
+                              Relocation {
+                                  address: 0x4,
+                                  symbol: Some("print".to_string()),
+                              }],
+            ..Object::default()
+        };
+        object.exports.insert("start".to_string(), 0x0);
+        object
+    }
+
+    fn write(object: &Object) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        object.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn it_reads_back_what_was_written() {
+        let bytes = write(&object());
+
+        assert!(is_object(&bytes));
+        assert_eq!(b"CHOB\x02\x04\x00\x00\x00\x05\x00\x00\x00".to_vec(), bytes[..13].to_vec());
+        assert_eq!(object(), Object::read_from(Cursor::new(bytes)).unwrap());
+    }
+
+    #[test]
+    fn it_refuses_relocations_for_symbols_it_doesnt_import() {
+        let mut object = object();
+        object.imports.clear();
+
+        assert!(object.write_to(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn it_rejects_other_files_versions_and_truncated_objects() {
+        let mut bytes = write(&object());
+        bytes[4] = 1;
+        assert!(Object::read_from(Cursor::new(bytes)).is_err());
+
+        let mut bytes = write(&object());
+        bytes[5] = 0;
+        assert!(Object::read_from(Cursor::new(bytes)).is_err());
+
+        assert!(Object::read_from(Cursor::new(b"CHSN\x01".to_vec())).is_err());
+
+        let mut bytes = write(&object());
+        let length = bytes.len();
+        bytes.truncate(length - 1);
+        assert!(Object::read_from(Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn it_finds_the_smallest_common_alignment() {
+        assert_eq!(Some(1), lcm(1, 1));
+        assert_eq!(Some(0xc), lcm(0x4, 0x6));
+        assert_eq!(Some(0x8), lcm(0x8, 0x2));
+        assert_eq!(None, lcm(0x1_0000, 0x1_0001));
+    }
+}
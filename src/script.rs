@@ -0,0 +1,258 @@
+//! Scripted keyboard input for reproducible runs.
+//!
+//! A script presses keys at fixed points in a program's execution, counted
+//! either in executed instructions or in frames drawn with `drw`. Running the
+//! same program with the same script always reads the same keys at the same
+//! time, which makes interactive programs testable.
+//!
+//! Scripts are plain text with one key press per line. Blank lines and
+//! anything after a `;` are ignored. Counts and keys are hex, like everywhere
+//! else, and keys can also be written as a quoted character.
+//!
+//! ```text
+//! ; Draw an `a` once the first frame is on screen, then quit
+//! frame 1 'a'
+//! step 200 3
+//! ```
+//!
+//! A `step` key is pressed once that many instructions have executed, and a
+//! `frame` key once that many frames have been drawn. Each key is read by
+//! one `key` instruction. When several keys are pressed before a `key`
+//! instruction runs, only the last one is read, just like a real keyboard.
+//! That's the key whose step or frame came latest, whatever order the script
+//! lists them in.
+//!
+//! ```
+//! use chifir::computer::Computer;
+//! use chifir::script::Script;
+//!
+//! let script = Script::parse("step 1 'a'").unwrap();
+//!
+//! let mut computer = Computer::new().script(script);
+//! computer.load(vec![
+//!     0x10, 0x0, 0x0, 0x0,  // nop
+//!     0xf, 0xc, 0x0, 0x0,   // key c
+//!     0x0, 0x0, 0x0, 0x0,   // brk
+//! ]);
+//!
+//! computer.run();
+//!
+//! assert_eq!(0x61, computer.peek(0xc));
+//! ```
+
+/// The point in execution at which a key is pressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// After this many instructions have executed.
+    Step(u64),
+    /// After this many frames have been drawn.
+    Frame(u64),
+}
+
+/// A single key press.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub trigger: Trigger,
+    pub key: u8,
+}
+
+/// A list of key presses, in the order they were written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Script {
+    events: Vec<Event>,
+    pressed: Vec<bool>,
+    // The step at which the frame of each `frame` key was drawn.
+    drawn: Vec<Option<u64>>,
+}
+
+impl Script {
+    /// Create a new `Script` that presses the keys in `events`.
+    pub fn new(events: Vec<Event>) -> Self {
+        let pressed = vec![false; events.len()];
+        let drawn = vec![None; events.len()];
+        Script { events, pressed, drawn }
+    }
+
+    /// Parses a script, reporting the line number of the first problem.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut events = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let words: Vec<&str> = strip_comment(line).split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+
+            let event = parse_event(&words).map_err(|error| {
+                format!("line {}: {}", index + 1, error)
+            })?;
+            events.push(event);
+        }
+
+        Ok(Script::new(events))
+    }
+
+    /// Returns the key presses in the script.
+    pub fn events(&self) -> &[Event] {
+        self.events.as_slice()
+    }
+
+    /// Returns the number of keys that haven't been pressed yet.
+    pub fn pending(&self) -> usize {
+        self.pressed.iter().filter(|pressed| !**pressed).count()
+    }
+
+    /// Starts the script over, so every key is pressed again.
+    pub fn reset(&mut self) {
+        for pressed in self.pressed.iter_mut() {
+            *pressed = false;
+        }
+        for drawn in self.drawn.iter_mut() {
+            *drawn = None;
+        }
+    }
+
+    /// Notes that `frames` frames have been drawn after `steps` instructions,
+    /// so keys pressed at a frame know when they were pressed.
+    pub fn drawn(&mut self, steps: u64, frames: u64) {
+        for (event, drawn) in self.events.iter().zip(self.drawn.iter_mut()) {
+            if let Trigger::Frame(frame) = event.trigger {
+                if frames >= frame && drawn.is_none() {
+                    *drawn = Some(steps);
+                }
+            }
+        }
+    }
+
+    /// Presses every key that is due after `steps` instructions and `frames`
+    /// frames, returning the one that was due last.
+    ///
+    /// Frame keys count as due at the step passed to `drawn` for their frame,
+    /// or at `steps` if their frame wasn't passed to `drawn`. Keys due at the
+    /// same step go by the order of the script.
+    pub fn press(&mut self, steps: u64, frames: u64) -> Option<u8> {
+        let mut latest: Option<(u64, u8)> = None;
+
+        let events = self.events.iter().zip(self.pressed.iter_mut()).zip(self.drawn.iter());
+        for ((event, pressed), drawn) in events {
+            let due = match event.trigger {
+                Trigger::Step(step) if steps >= step => step,
+                Trigger::Frame(frame) if frames >= frame => drawn.unwrap_or(steps),
+                _ => continue,
+            };
+
+            if !*pressed {
+                *pressed = true;
+                match latest {
+                    Some((step, _)) if step > due => {}
+                    _ => latest = Some((due, event.key)),
+                }
+            }
+        }
+
+        latest.map(|(_, key)| key)
+    }
+}
+
+// Removes everything after a `;` that isn't a quoted character.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+
+    for (index, character) in line.char_indices() {
+        match character {
+            '\'' => quoted = !quoted,
+            ';' if !quoted => return &line[..index],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+fn parse_event(words: &[&str]) -> Result<Event, String> {
+    if words.len() != 3 {
+        return Err("expected `step count key` or `frame count key`".to_string());
+    }
+
+    let count = u64::from_str_radix(words[1], 16)
+        .map_err(|_| format!("invalid count `{}`", words[1]))?;
+
+    let trigger = match words[0] {
+        "step" => Trigger::Step(count),
+        "frame" => Trigger::Frame(count),
+        other => return Err(format!("unknown trigger `{}`", other)),
+    };
+
+    Ok(Event {
+        trigger,
+        key: parse_key(words[2])?,
+    })
+}
+
+fn parse_key(key: &str) -> Result<u8, String> {
+    let bytes = key.as_bytes();
+
+    if bytes.len() == 3 && bytes[0] == b'\'' && bytes[2] == b'\'' && bytes[1].is_ascii() {
+        return Ok(bytes[1]);
+    }
+
+    u8::from_str_radix(key, 16).map_err(|_| format!("invalid key `{}`", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Script, Trigger};
+
+    #[test]
+    fn it_parses_scripts() {
+        let script = Script::parse("
+        ; Comments and blank lines are ignored
+        step 10 61
+        frame 2 ';'  ; quoted characters
+        ")
+            .unwrap();
+
+        assert_eq!(&[Event {
+                         trigger: Trigger::Step(0x10),
+                         key: 0x61,
+                     },
+                     Event {
+                         trigger: Trigger::Frame(2),
+                         key: b';',
+                     }],
+                   script.events());
+    }
+
+    #[test]
+    fn it_reports_the_line_of_a_problem() {
+        assert_eq!(Err("line 2: unknown trigger `tick`".to_string()),
+                   Script::parse("step 1 61\ntick 2 62"));
+        assert_eq!(Err("line 1: invalid key `100`".to_string()),
+                   Script::parse("frame 1 100"));
+        assert_eq!(Err("line 1: expected `step count key` or `frame count key`".to_string()),
+                   Script::parse("step 1"));
+    }
+
+    #[test]
+    fn it_presses_each_key_once_when_due() {
+        let mut script = Script::parse("step 2 61\nframe 1 62\nstep 3 63").unwrap();
+
+        assert_eq!(None, script.press(1, 0));
+        assert_eq!(Some(0x61), script.press(2, 0));
+        assert_eq!(None, script.press(2, 0));
+        script.drawn(2, 1);
+        assert_eq!(Some(0x63), script.press(5, 1));
+        assert_eq!(0, script.pending());
+    }
+
+    #[test]
+    fn it_presses_the_key_that_was_due_last() {
+        let mut script = Script::parse("frame 1 'a'\nstep 5 'b'").unwrap();
+        script.drawn(200, 1);
+        assert_eq!(Some(b'a'), script.press(200, 1));
+
+        script.reset();
+        script.drawn(3, 1);
+        assert_eq!(Some(b'b'), script.press(200, 1));
+    }
+}
@@ -1,3 +1,12 @@
+use std::collections::HashMap;
+use std::iter;
+
+// Terminals commonly have 256 color registers.
+const REGISTERS: usize = 256;
+
+// Runs shorter than this are cheaper to write out than to repeat.
+const SHORTEST_REPEAT: usize = 4;
+
 pub fn begin() -> String {
     "\x1bPq".to_string()
 }
@@ -11,16 +20,16 @@ pub fn from(memory: &[u32], width: usize, height: usize, border: bool) -> String
     let mut row = 0;
 
     if border {
-        for _ in 0..(width + 2) {
-            pixels.push(95);
-        }
+        push_runs(&mut pixels, &iter::repeat_n(95, width + 2).collect::<Vec<u8>>());
         pixels.push(36);
         pixels.push(45);
     }
 
     while row < height {
+        let mut line = Vec::with_capacity(width + 2);
+
         if border {
-            pixels.push(126);
+            line.push(126);
         }
 
         for x in 0..width {
@@ -28,20 +37,20 @@ pub fn from(memory: &[u32], width: usize, height: usize, border: bool) -> String
 
             for y in 0..6 {
                 let offset = x + ((row + y) * width);
-                if offset < memory.len() {
-                    if memory[offset] > 0 {
-                        byte = byte | (1 << y);
-                    }
+                if offset < memory.len() && memory[offset] > 0 {
+                    byte |= 1 << y;
                 }
             }
 
-            pixels.push(byte + 63);
+            line.push(byte + 63);
         }
 
         if border {
-            pixels.push(126);
+            line.push(126);
         }
 
+        push_runs(&mut pixels, &line);
+
         // Push "$-" to move to the next row.
         pixels.push(36);
         pixels.push(45);
@@ -49,9 +58,7 @@ pub fn from(memory: &[u32], width: usize, height: usize, border: bool) -> String
     }
 
     if border {
-        for _ in 0..(width + 2) {
-            pixels.push(64);
-        }
+        push_runs(&mut pixels, &iter::repeat_n(64, width + 2).collect::<Vec<u8>>());
         pixels.push(36);
         pixels.push(45);
     }
@@ -59,8 +66,167 @@ pub fn from(memory: &[u32], width: usize, height: usize, border: bool) -> String
     String::from_utf8(pixels).unwrap()
 }
 
+/// Encodes pixels given as 0xRRGGBB colors.
+///
+/// Every distinct color gets a color register, numbered in the order the
+/// colors first appear. Each band of six rows is then drawn once per color
+/// in it, returning to the start of the band with `$` in between. Images
+/// with more colors than there are registers are reduced to 3 bits of red
+/// and green and 2 bits of blue.
+pub fn from_colors(colors: &[u32], width: usize, height: usize) -> String {
+    let mut colors: Vec<u32> = colors.iter().map(|color| color & 0xff_ffff).collect();
+    let mut registers = distinct(&colors);
+
+    if registers.len() > REGISTERS {
+        for color in colors.iter_mut() {
+            *color &= 0xe0_e0c0;
+        }
+        registers = distinct(&colors);
+    }
+
+    let mut pixels = Vec::new();
+
+    for (register, color) in registers.iter().enumerate() {
+        let percent = |shift: u32| ((color >> shift & 0xff) * 100 + 127) / 255;
+        pixels.extend_from_slice(format!("#{};2;{};{};{}",
+                                         register,
+                                         percent(16),
+                                         percent(8),
+                                         percent(0))
+            .as_bytes());
+    }
+
+    let mut row = 0;
+
+    while row < height {
+        for (register, color) in registers.iter().enumerate() {
+            let mut band = Vec::with_capacity(width);
+
+            for x in 0..width {
+                let mut byte: u8 = 0;
+
+                for y in 0..6 {
+                    let offset = x + ((row + y) * width);
+                    if row + y < height && offset < colors.len() && colors[offset] == *color {
+                        byte |= 1 << y;
+                    }
+                }
+
+                band.push(byte + 63);
+            }
+
+            if band.iter().any(|byte| *byte != 63) {
+                pixels.extend_from_slice(format!("#{}", register).as_bytes());
+                push_runs(&mut pixels, &band);
+                pixels.push(36);
+            }
+        }
+
+        pixels.push(45);
+        row += 6;
+    }
+
+    String::from_utf8(pixels).unwrap()
+}
+
+// Appends `line` to `pixels`, replacing runs of the same sixel with the
+// repeat introducer `!`, the run length in decimal and the sixel.
+fn push_runs(pixels: &mut Vec<u8>, line: &[u8]) {
+    let mut start = 0;
+
+    while start < line.len() {
+        let byte = line[start];
+        let length = line[start..].iter().take_while(|other| **other == byte).count();
+
+        if length >= SHORTEST_REPEAT {
+            pixels.extend_from_slice(format!("!{}", length).as_bytes());
+            pixels.push(byte);
+        } else {
+            pixels.extend(iter::repeat_n(byte, length));
+        }
+
+        start += length;
+    }
+}
+
+// Returns the colors in `colors` without repeats, in the order they first
+// appear.
+fn distinct(colors: &[u32]) -> Vec<u32> {
+    let mut seen = HashMap::new();
+    let mut distinct = Vec::new();
+
+    for color in colors {
+        if seen.insert(*color, ()).is_none() {
+            distinct.push(*color);
+        }
+    }
+
+    distinct
+}
+
 #[cfg(test)]
 mod tests {
+    use std::iter::Peekable;
+    use std::str::Bytes;
+
+    fn number(bytes: &mut Peekable<Bytes>) -> usize {
+        let mut number = 0;
+        while let Some(digit) = bytes.peek().cloned().filter(u8::is_ascii_digit) {
+            number = number * 10 + (digit - b'0') as usize;
+            bytes.next();
+        }
+        number
+    }
+
+    // Decodes sixel data into the color register drawn at each pixel, with
+    // None for pixels left blank. Register 0 is used until one is selected.
+    fn decode(data: &str, width: usize, height: usize) -> Vec<Option<usize>> {
+        let mut pixels = vec![None; width * height];
+        let mut bytes = data.bytes().peekable();
+        let (mut x, mut row, mut register) = (0, 0, 0);
+
+        while let Some(byte) = bytes.next() {
+            let (count, sixel) = match byte {
+                b'$' => {
+                    x = 0;
+                    continue;
+                }
+                b'-' => {
+                    x = 0;
+                    row += 6;
+                    continue;
+                }
+                b'#' => {
+                    register = number(&mut bytes);
+                    // Skip color definitions, which don't select a register.
+                    while bytes.peek() == Some(&b';') {
+                        bytes.next();
+                        number(&mut bytes);
+                    }
+                    continue;
+                }
+                b'!' => (number(&mut bytes), bytes.next().unwrap()),
+                sixel => (1, sixel),
+            };
+
+            for _ in 0..count {
+                for y in 0..6 {
+                    if (sixel - 63) & (1 << y) != 0 && x < width && row + y < height {
+                        pixels[(row + y) * width + x] = Some(register);
+                    }
+                }
+                x += 1;
+            }
+        }
+
+        pixels
+    }
+
+    // A test pattern with long runs, short runs and single pixels.
+    fn pattern(width: usize, height: usize) -> Vec<u32> {
+        (0..width * height).map(|index| (index % width / 5 + index / width / 2) as u32 % 3).collect()
+    }
+
     #[test]
     fn it_converts_000000_to_question_mark() {
         assert_eq!(super::from(&[0, 0, 0, 0, 0, 0], 1, 6, false), "?$-");
@@ -90,4 +256,57 @@ mod tests {
                                false),
                    "]DD]$-");
     }
+
+    #[test]
+    fn it_repeats_runs_of_four_or_more_sixels() {
+        assert_eq!(super::from(&[1, 1, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0], 12, 1, false),
+                   "@@@!4?@!4?$-");
+        assert_eq!(super::from(&[0; 20], 20, 6, true), "!22_$-~!20?~$-!22@$-");
+    }
+
+    #[test]
+    fn it_decodes_compressed_frames_to_the_same_pixels() {
+        let (width, height) = (47, 20);
+        let memory = pattern(width, height);
+
+        let decoded = decode(&super::from(&memory, width, height, false), width, height);
+        for (pixel, register) in memory.iter().zip(decoded) {
+            assert_eq!(*pixel > 0, register.is_some());
+        }
+
+        let colors: Vec<u32> = memory.iter().map(|pixel| pixel * 0x40_0000).collect();
+        let decoded = decode(&super::from_colors(&colors, width, height), width, height);
+        for (color, register) in colors.iter().zip(decoded) {
+            assert_eq!(Some((*color / 0x40_0000) as usize), register);
+        }
+    }
+
+    #[test]
+    fn it_compresses_a_blank_display() {
+        let memory = vec![0; 512 * 684];
+
+        assert_eq!(114 * 7, super::from(&memory, 512, 684, false).len());
+    }
+
+    #[test]
+    fn it_defines_a_register_for_each_color() {
+        assert_eq!(super::from_colors(&[0xffffff, 0x000000, 0x808080, 0x0], 2, 2),
+                   "#0;2;100;100;100#1;2;0;0;0#2;2;50;50;50#0@?$#1?B$#2A?$-");
+    }
+
+    #[test]
+    fn it_skips_colors_missing_from_a_band() {
+        let colors = [0xff0000; 6].iter().chain([0x00ff00; 6].iter()).cloned().collect::<Vec<u32>>();
+
+        assert_eq!(super::from_colors(&colors, 1, 12), "#0;2;100;0;0#1;2;0;100;0#0~$-#1~$-");
+    }
+
+    #[test]
+    fn it_reduces_images_with_too_many_colors() {
+        let colors: Vec<u32> = (0..300).collect();
+        let pixels = super::from_colors(&colors, 300, 1);
+
+        assert!(pixels.starts_with("#0;2;0;0;0#1;2;0;0;25#2;2;0;0;50#3;2;0;0;75#0"));
+        assert!(!pixels.contains("#4;"));
+    }
 }
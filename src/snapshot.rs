@@ -0,0 +1,356 @@
+//! Saving and restoring the complete state of a computer.
+//!
+//! A snapshot holds everything a program can observe: memory, the program
+//! counter, the execution state, the keyboard latch and the display
+//! configuration. It also keeps the last drawn frame, so the screen can be
+//! redrawn after restoring. Breakpoints, watchpoints, history and the bound
+//! input and output aren't part of a snapshot.
+//!
+//! ```
+//! use std::io::Cursor;
+//! use chifir::computer::Computer;
+//! use chifir::snapshot::Snapshot;
+//!
+//! let mut computer = Computer::new();
+//! computer.load(vec![
+//!     0x10, 0x0, 0x0, 0x0,  // nop
+//!     0x0, 0x0, 0x0, 0x0,   // brk
+//! ]);
+//! computer.step();
+//!
+//! let mut file = Vec::new();
+//! computer.snapshot().write_to(&mut file).unwrap();
+//!
+//! let mut resumed = Computer::new();
+//! resumed.restore(&Snapshot::read_from(Cursor::new(file)).unwrap());
+//!
+//! assert_eq!(0x4, resumed.counter());
+//! assert_eq!(computer.dump(), resumed.dump());
+//! ```
+//!
+//! # Format
+//!
+//! Snapshots start with the four bytes `CHSN` and a version byte, currently
+//! 2. Numbers are little endian and the fields follow one after another.
+//!
+//! |Field              |Size                                          |
+//! |:------------------|:---------------------------------------------|
+//! |PC                 |4 bytes                                       |
+//! |State              |1 byte, 0 running, 1 halted, 2 illegal opcode, 3 waiting for input, 4 trace failed, 5 display failed|
+//! |Illegal opcode     |4 bytes, zero unless the state is 2           |
+//! |Keyboard           |1 byte flag, 1 if a key is latched, then 1 byte key|
+//! |Display            |4 bytes each for the address, width and height|
+//! |Accessed length    |8 bytes, how far `dump` reaches                |
+//! |Page count         |4 bytes                                       |
+//! |Pages              |4 byte page number then 4096 words, for each page|
+//! |Frame              |1 byte flag, 1 if a frame has been drawn      |
+//! |Frame size         |4 bytes each for the width and height         |
+//! |Frame pixels       |One word per pixel, row by row                |
+//!
+//! The frame size and pixels are only present when the flag is set. Both
+//! widths carry the display mode in their top byte, like the width given to
+//! `cfv`. Version 1 snapshots stored the frame as Sixel graphics and can't
+//! be read anymore.
+
+use super::computer::State;
+use super::display::{Frame, Mode};
+use super::memory::{Memory, PAGE_SIZE};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"CHSN";
+const VERSION: u8 = 2;
+
+// Pages are numbered by the top 20 bits of their addresses.
+const PAGE_COUNT: u64 = 1 << 20;
+
+/// The complete state of a `Computer`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub counter: u32,
+    pub state: State,
+    pub keyboard: Option<u8>,
+    pub display_address: u32,
+    pub display_width: u32,
+    pub display_height: u32,
+    pub display_mode: Mode,
+    /// The last frame drawn with `drw`.
+    pub frame: Option<Frame>,
+    pub(crate) memory: Memory,
+}
+
+impl Snapshot {
+    /// Returns the value in memory at `address`.
+    pub fn peek(&self, address: u32) -> u32 {
+        self.memory.peek(address)
+    }
+
+    /// Extracts a slice containing the contents of memory, like
+    /// `Computer::dump`.
+    pub fn dump(&self) -> &[u32] {
+        self.memory.as_slice()
+    }
+
+    /// Writes the snapshot to `writer`.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+
+        put_u32(&mut bytes, self.counter);
+        let (state, opcode) = match self.state {
+            State::Running => (0, 0),
+            State::Halted => (1, 0),
+            State::IllegalOpcode(opcode) => (2, opcode),
+            State::WaitingForInput => (3, 0),
+            State::TraceFailed => (4, 0),
+            State::DisplayFailed => (5, 0),
+        };
+        bytes.push(state);
+        put_u32(&mut bytes, opcode);
+
+        match self.keyboard {
+            Some(key) => bytes.extend_from_slice(&[1, key]),
+            None => bytes.extend_from_slice(&[0, 0]),
+        }
+
+        put_u32(&mut bytes, self.display_address);
+        put_u32(&mut bytes, with_mode(self.display_width, self.display_mode));
+        put_u32(&mut bytes, self.display_height);
+
+        bytes.extend_from_slice(&self.memory.len().to_le_bytes());
+        let pages = self.memory.allocated_pages();
+        put_u32(&mut bytes, pages.len() as u32);
+        for (number, page) in pages {
+            put_u32(&mut bytes, number);
+            for word in page.iter() {
+                put_u32(&mut bytes, *word);
+            }
+        }
+
+        match self.frame {
+            Some(ref frame) => {
+                bytes.push(1);
+                put_u32(&mut bytes, with_mode(frame.width, frame.mode));
+                put_u32(&mut bytes, frame.height);
+                for pixel in &frame.pixels {
+                    put_u32(&mut bytes, *pixel);
+                }
+            }
+            None => bytes.push(0),
+        }
+
+        writer.write_all(&bytes)?;
+        writer.flush()
+    }
+
+    /// Reads a snapshot from `reader`, checking the header and version.
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+
+        if &header[0..4] != MAGIC {
+            return Err(invalid("not a Chifir snapshot".to_string()));
+        }
+        if header[4] != VERSION {
+            return Err(invalid(format!("unsupported snapshot version {}", header[4])));
+        }
+
+        let counter = get_u32(&mut reader)?;
+        let tag = get_u8(&mut reader)?;
+        let opcode = get_u32(&mut reader)?;
+        let state = match tag {
+            0 => State::Running,
+            1 => State::Halted,
+            2 => State::IllegalOpcode(opcode),
+            3 => State::WaitingForInput,
+            4 => State::TraceFailed,
+            5 => State::DisplayFailed,
+            _ => return Err(invalid(format!("unknown state {}", tag))),
+        };
+
+        let latched = get_u8(&mut reader)?;
+        let key = get_u8(&mut reader)?;
+        let keyboard = if latched != 0 { Some(key) } else { None };
+
+        let display_address = get_u32(&mut reader)?;
+        let (display_width, display_mode) = without_mode(get_u32(&mut reader)?);
+        let display_height = get_u32(&mut reader)?;
+
+        let mut len = [0; 8];
+        reader.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        if len > 1 << 32 {
+            return Err(invalid(format!("memory length {:x} is too large", len)));
+        }
+
+        let mut memory = Memory::new();
+        let count = get_u32(&mut reader)?;
+        if count as u64 > PAGE_COUNT {
+            return Err(invalid(format!("too many pages ({})", count)));
+        }
+
+        for _ in 0..count {
+            let number = get_u32(&mut reader)?;
+            if number as u64 >= PAGE_COUNT {
+                return Err(invalid(format!("page number {:x} is out of range", number)));
+            }
+
+            let mut page = Box::new([0; PAGE_SIZE]);
+            for word in page.iter_mut() {
+                *word = get_u32(&mut reader)?;
+            }
+            memory.insert_page(number, page);
+        }
+        memory.set_len(len);
+
+        let frame = if get_u8(&mut reader)? != 0 {
+            let (width, mode) = without_mode(get_u32(&mut reader)?);
+            let height = get_u32(&mut reader)?;
+            let size = width as u64 * height as u64;
+            if size > 1 << 32 {
+                return Err(invalid(format!("frame size {:x} by {:x} is too large", width, height)));
+            }
+
+            // The pixels are read one at a time, so a corrupt size fails on
+            // the end of the file rather than on allocating memory.
+            let mut pixels = Vec::new();
+            for _ in 0..size {
+                pixels.push(get_u32(&mut reader)?);
+            }
+
+            Some(Frame {
+                width,
+                height,
+                mode,
+                pixels,
+            })
+        } else {
+            None
+        };
+
+        Ok(Snapshot {
+            counter,
+            state,
+            keyboard,
+            display_address,
+            display_width,
+            display_height,
+            display_mode,
+            frame,
+            memory,
+        })
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Packs a width and a mode into one word the way `cfv` takes them.
+fn with_mode(width: u32, mode: Mode) -> u32 {
+    mode.number() << 24 | width & 0xff_ffff
+}
+
+fn without_mode(word: u32) -> (u32, Mode) {
+    (word & 0xff_ffff, Mode::from_number(word >> 24))
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn get_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn get_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Snapshot;
+    use computer::State;
+    use display::{Frame, Mode};
+    use memory::Memory;
+    use std::io::Cursor;
+
+    fn snapshot() -> Snapshot {
+        let mut memory = Memory::new();
+        memory.write(0x4, 7);
+        memory.write(0xFFFF_FFF0, 8);
+        memory.read(0x10);
+
+        Snapshot {
+            counter: 0x8,
+            state: State::IllegalOpcode(0x99),
+            keyboard: Some(0x61),
+            display_address: 0x100,
+            display_width: 0x10,
+            display_height: 0x8,
+            display_mode: Mode::Monochrome,
+            frame: Some(Frame {
+                width: 2,
+                height: 1,
+                mode: Mode::Monochrome,
+                pixels: vec![0, 1],
+            }),
+            memory,
+        }
+    }
+
+    fn write(snapshot: &Snapshot) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        snapshot.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn it_reads_back_what_was_written() {
+        let bytes = write(&snapshot());
+
+        assert_eq!(snapshot(), Snapshot::read_from(Cursor::new(bytes)).unwrap());
+    }
+
+    #[test]
+    fn it_only_writes_allocated_pages() {
+        let bytes = write(&snapshot());
+
+        assert_eq!(b"CHSN\x02", &bytes[0..5]);
+        assert_eq!(5 + 4 + 1 + 4 + 2 + 12 + 8 + 4 + 2 * (4 + 4096 * 4) + 1 + 8 + 2 * 4,
+                   bytes.len());
+    }
+
+    #[test]
+    fn it_keeps_the_display_mode_in_the_width() {
+        let mut snapshot = snapshot();
+        snapshot.display_mode = Mode::Rgb;
+        snapshot.frame.as_mut().unwrap().mode = Mode::Palette;
+
+        let bytes = write(&snapshot);
+        assert_eq!(&[0x10, 0, 0, 3], &bytes[20..24]);
+        assert_eq!(snapshot, Snapshot::read_from(Cursor::new(bytes)).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_other_files_and_versions() {
+        let mut bytes = write(&snapshot());
+        bytes[4] = 1;
+
+        assert!(Snapshot::read_from(Cursor::new(bytes)).is_err());
+        assert!(Snapshot::read_from(Cursor::new(b"CHTR\x01".to_vec())).is_err());
+    }
+
+    #[test]
+    fn it_reports_truncated_snapshots() {
+        let mut bytes = write(&snapshot());
+        let length = bytes.len();
+        bytes.truncate(length - 1);
+
+        assert!(Snapshot::read_from(Cursor::new(bytes)).is_err());
+    }
+}
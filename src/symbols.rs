@@ -0,0 +1,126 @@
+//! Looking up and describing addresses by label name.
+//!
+//! The tools built on top of the computer, like the debugger and the trace
+//! printer, show addresses in hex along with the closest label.
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use chifir::symbols::Symbols;
+//!
+//! let mut labels = HashMap::new();
+//! labels.insert("loop".to_string(), 0x4);
+//!
+//! let symbols = Symbols::new(labels);
+//!
+//! assert_eq!(Ok(0xc), symbols.resolve("loop+8"));
+//! assert_eq!("0000000c <loop+8>", symbols.describe(0xc));
+//! ```
+
+use std::collections::HashMap;
+
+pub struct Symbols {
+    labels: HashMap<String, u32>,
+}
+
+impl Symbols {
+    /// Create a new `Symbols` from a map of label names to addresses.
+    pub fn new(labels: HashMap<String, u32>) -> Self {
+        Symbols { labels }
+    }
+
+    /// Turns a label or hex address, optionally followed by a hex offset like
+    /// `font-a+4`, into an address.
+    pub fn resolve(&self, address: &str) -> Result<u32, String> {
+        let (base, offset) = match address.rfind('+') {
+            Some(index) => (&address[..index], Some(&address[index + 1..])),
+            None => (address, None),
+        };
+
+        let base = match self.labels.get(base) {
+            Some(location) => *location,
+            None => {
+                u32::from_str_radix(base, 16)
+                    .map_err(|_| format!("unknown label or address `{}`", base))?
+            }
+        };
+
+        match offset {
+            Some(offset) => {
+                let offset = u32::from_str_radix(offset, 16)
+                    .map_err(|_| format!("invalid number `{}`", offset))?;
+                Ok(base.wrapping_add(offset))
+            }
+            None => Ok(base),
+        }
+    }
+
+    /// Formats an address in hex along with the closest label at or before
+    /// it.
+    pub fn describe(&self, address: u32) -> String {
+        let closest = self.labels
+            .iter()
+            .filter(|&(_, location)| *location <= address)
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)));
+
+        match closest {
+            Some((label, location)) if *location == address => {
+                format!("{:08x} <{}>", address, label)
+            }
+            Some((label, location)) => {
+                format!("{:08x} <{}+{:x}>", address, label, address - location)
+            }
+            None => format!("{:08x}", address),
+        }
+    }
+
+    /// Returns the alphabetically first label at exactly `address`.
+    pub fn label_at(&self, address: u32) -> Option<&str> {
+        self.labels
+            .iter()
+            .filter(|&(_, location)| *location == address)
+            .map(|(label, _)| label.as_str())
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Symbols;
+    use std::collections::HashMap;
+
+    fn symbols() -> Symbols {
+        let mut labels = HashMap::new();
+        labels.insert("start".to_string(), 0x0);
+        labels.insert("loop".to_string(), 0x8);
+        labels.insert("again".to_string(), 0x8);
+        Symbols::new(labels)
+    }
+
+    #[test]
+    fn it_resolves_labels_and_hex_addresses() {
+        let s = symbols();
+
+        assert_eq!(Ok(0x8), s.resolve("loop"));
+        assert_eq!(Ok(0x1f), s.resolve("1f"));
+        assert_eq!(Ok(0x18), s.resolve("loop+10"));
+        assert_eq!(Err("unknown label or address `nowhere`".to_string()),
+                   s.resolve("nowhere"));
+    }
+
+    #[test]
+    fn it_describes_addresses_with_the_closest_label() {
+        let s = symbols();
+
+        assert_eq!("00000004 <start+4>", s.describe(0x4));
+        assert_eq!("00000008 <again>", s.describe(0x8));
+        assert_eq!("0000000c <again+4>", s.describe(0xc));
+    }
+
+    #[test]
+    fn it_prefers_the_first_label_alphabetically() {
+        let s = symbols();
+
+        assert_eq!(Some("again"), s.label_at(0x8));
+        assert_eq!(None, s.label_at(0x4));
+    }
+}
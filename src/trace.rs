@@ -0,0 +1,312 @@
+//! Recording and reading execution traces.
+//!
+//! A trace has one record for every instruction a `Computer` executes. Each
+//! record holds the program counter, the opcode and operands, the memory the
+//! instruction wrote along with the value it replaced, and the key it read.
+//!
+//! ```
+//! use std::env;
+//! use std::fs::{self, File};
+//! use std::process;
+//! use chifir::computer::Computer;
+//! use chifir::trace::{Store, TraceReader};
+//!
+//! let path = env::temp_dir().join(format!("chifir-trace-example-{}.trace", process::id()));
+//!
+//! let mut computer = Computer::new().trace(Box::new(File::create(&path).unwrap())).unwrap();
+//! computer.load(vec![
+//!     0x4, 0x8, 0x9, 0x0,  // lea 8 9
+//!     0x0, 0x0, 0x0, 0x0,  // brk
+//!     0x1, 0x2,
+//! ]);
+//!
+//! computer.run();
+//! drop(computer);
+//!
+//! let records: Vec<_> = TraceReader::new(File::open(&path).unwrap()).unwrap()
+//!     .map(|record| record.unwrap())
+//!     .collect();
+//!
+//! assert_eq!(2, records.len());
+//! assert_eq!(Some(Store { address: 0x8, old: 0x1, new: 0x2 }), records[0].store);
+//! # fs::remove_file(&path).unwrap();
+//! ```
+//!
+//! # Format
+//!
+//! Traces start with the four bytes `CHTR` and a version byte, currently 1.
+//! Records follow one after another until the end of the file. Every number
+//! is written as an unsigned LEB128 variable length integer, so small values
+//! take a single byte.
+//!
+//! |Field              |Present                     |
+//! |:------------------|:---------------------------|
+//! |Flags              |Always                      |
+//! |PC                 |Always                      |
+//! |Opcode, A, B, C    |Always                      |
+//! |Address, old, new  |When flag bit 0 is set      |
+//! |Key                |When flag bit 1 is set      |
+
+use std::io::{self, BufReader, Read, Write};
+
+const MAGIC: &[u8; 4] = b"CHTR";
+const VERSION: u8 = 1;
+
+const HAS_STORE: u32 = 1;
+const HAS_KEY: u32 = 2;
+
+/// A write to memory made by an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Store {
+    pub address: u32,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// A single executed instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub pc: u32,
+    pub opcode: u32,
+    pub a: u32,
+    pub b: u32,
+    pub c: u32,
+    pub store: Option<Store>,
+    pub key: Option<u8>,
+}
+
+/// Writes records to a trace.
+pub struct TraceWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Create a new `TraceWriter`, writing the trace header to `inner`.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&[VERSION])?;
+        Ok(TraceWriter { inner })
+    }
+
+    /// Appends `record` to the trace.
+    pub fn record(&mut self, record: &Record) -> io::Result<()> {
+        let mut flags = 0;
+        if record.store.is_some() {
+            flags |= HAS_STORE;
+        }
+        if record.key.is_some() {
+            flags |= HAS_KEY;
+        }
+
+        let mut bytes = Vec::with_capacity(16);
+        encode(&mut bytes, flags);
+        encode(&mut bytes, record.pc);
+        encode(&mut bytes, record.opcode);
+        encode(&mut bytes, record.a);
+        encode(&mut bytes, record.b);
+        encode(&mut bytes, record.c);
+
+        if let Some(store) = record.store {
+            encode(&mut bytes, store.address);
+            encode(&mut bytes, store.old);
+            encode(&mut bytes, store.new);
+        }
+
+        if let Some(key) = record.key {
+            encode(&mut bytes, key as u32);
+        }
+
+        self.inner.write_all(&bytes)
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads records from a trace.
+///
+/// Records are read one at a time through the `Iterator` trait, so traces
+/// larger than memory can be read.
+pub struct TraceReader<R: Read> {
+    inner: io::Bytes<BufReader<R>>,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Create a new `TraceReader`, checking the trace header in `inner`.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0; 5];
+        inner.read_exact(&mut header)?;
+
+        if &header[0..4] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a Chifir trace"));
+        }
+        if header[4] != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("unsupported trace version {}", header[4])));
+        }
+
+        Ok(TraceReader { inner: BufReader::new(inner).bytes() })
+    }
+
+    fn decode(&mut self) -> io::Result<Option<u32>> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = match self.inner.next() {
+                Some(byte) => byte?,
+                None if shift == 0 => return Ok(None),
+                None => return Err(truncated()),
+            };
+
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if shift > 28 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "number is too large"));
+            }
+        }
+
+        if value > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "number is too large"));
+        }
+
+        Ok(Some(value as u32))
+    }
+
+    fn field(&mut self) -> io::Result<u32> {
+        self.decode()?.ok_or_else(truncated)
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let flags = match self.decode()? {
+            Some(flags) => flags,
+            None => return Ok(None),
+        };
+
+        let mut record = Record {
+            pc: self.field()?,
+            opcode: self.field()?,
+            a: self.field()?,
+            b: self.field()?,
+            c: self.field()?,
+            store: None,
+            key: None,
+        };
+
+        if flags & HAS_STORE != 0 {
+            record.store = Some(Store {
+                address: self.field()?,
+                old: self.field()?,
+                new: self.field()?,
+            });
+        }
+
+        if flags & HAS_KEY != 0 {
+            record.key = Some(self.field()? as u8);
+        }
+
+        Ok(Some(record))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        self.read_record().transpose()
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "trace ends in the middle of a record")
+}
+
+// Writes `value` as an unsigned LEB128 number.
+fn encode(bytes: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Record, Store, TraceReader, TraceWriter};
+    use std::io::Cursor;
+
+    fn record() -> Record {
+        Record {
+            pc: 0x10,
+            opcode: 6,
+            a: 0x20,
+            b: 0x21,
+            c: 0,
+            store: Some(Store {
+                address: 0xFFFF_FFFF,
+                old: 0,
+                new: 300,
+            }),
+            key: Some(0x61),
+        }
+    }
+
+    #[test]
+    fn it_writes_small_records_compactly() {
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        writer.record(&Record {
+                pc: 4,
+                opcode: 16,
+                a: 0,
+                b: 0,
+                c: 0,
+                store: None,
+                key: None,
+            })
+            .unwrap();
+
+        assert_eq!(b"CHTR\x01\x00\x04\x10\x00\x00\x00".to_vec(), writer.inner);
+    }
+
+    #[test]
+    fn it_reads_back_what_was_written() {
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        writer.record(&record()).unwrap();
+        writer.record(&record()).unwrap();
+
+        let reader = TraceReader::new(Cursor::new(writer.inner)).unwrap();
+        let records: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+
+        assert_eq!(vec![record(), record()], records);
+    }
+
+    #[test]
+    fn it_rejects_files_that_are_not_traces() {
+        assert!(TraceReader::new(Cursor::new(b"CHSN\x01".to_vec())).is_err());
+        assert!(TraceReader::new(Cursor::new(b"CHTR\x02".to_vec())).is_err());
+    }
+
+    #[test]
+    fn it_reports_truncated_records() {
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        writer.record(&record()).unwrap();
+        let length = writer.inner.len();
+        writer.inner.truncate(length - 1);
+
+        let mut reader = TraceReader::new(Cursor::new(writer.inner)).unwrap();
+
+        assert!(reader.next().unwrap().is_err());
+    }
+}